@@ -0,0 +1,222 @@
+use crate::config::{self, ConfigFile, ConfigFormat, WindowConfig};
+use std::fmt::Write;
+use tracing::level_filters::LevelFilter;
+
+/// What to do when kester is started from the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CliCommand {
+    /// No subcommand: start the GUI.
+    Gui,
+    /// Print the config file JSON Schema.
+    Schema,
+    /// List profiles, or switch to the named one.
+    Profile(Option<String>),
+    /// Show every config layer and rule with the file it came from, or which rule matches a window.
+    Explain(Option<String>),
+    /// Make every window fully opaque and pause rule application.
+    Reset,
+    /// Apply rules again after a reset.
+    Resume,
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cli {
+    pub command: CliCommand,
+    pub config_path: Option<String>,
+    pub format: Option<ConfigFormat>,
+    /// Most detailed log entries to write, if set on the command line.
+    pub log_level: Option<LevelFilter>,
+}
+
+impl Cli {
+    pub fn config_file(&self) -> ConfigFile {
+        let path = self.config_path.clone().unwrap_or_else(ConfigFile::default_path);
+        ConfigFile::new(&path, self.format)
+    }
+}
+
+pub const USAGE: &str = "\
+Usage: kester [OPTIONS] [COMMAND]
+
+Commands:
+  schema            Print the JSON Schema for config.yaml
+  profile [NAME]    List profiles, or switch to NAME (`default` for the top-level settings)
+  explain [WINDOW]  Show config layers and rules with the file each came from, or which rule
+                    matches a window title or executable name
+  reset             Make every window fully opaque right away and pause rule application
+  resume            Apply rules again after a reset
+  help              Print this help
+
+Options:
+  --config <PATH>    Config file to use (default: config.yaml, or config.toml/config.json if present)
+  --format <FORMAT>  Config file format: yaml, toml or json (default: from the file extension)
+  --log-level <LEVEL>
+                     Log detail: off, error, warn, info, debug or trace (default: info). The log is
+                     written to kester.<date>.log next to the config file, one file per day
+  -v, --verbose      Same as --log-level debug
+
+Without a command, the Transparency Manager window is started.";
+
+pub fn parse_args<I>(args: I) -> Result<Cli, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut cli = Cli { command: CliCommand::Gui, config_path: None, format: None, log_level: None };
+    let mut command = None;
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                cli.config_path = Some(args.next().ok_or("--config needs a path")?);
+            }
+            "--format" => {
+                let name = args.next().ok_or("--format needs a value")?;
+                cli.format = Some(ConfigFormat::from_name(&name)
+                    .ok_or_else(|| format!("unknown config format `{}`, expected yaml, toml or json", name))?);
+            }
+            "--log-level" => {
+                let level = args.next().ok_or("--log-level needs a value")?;
+                cli.log_level = Some(level.parse()
+                    .map_err(|_| format!("unknown log level `{}`, expected off, error, warn, info, debug or trace", level))?);
+            }
+            "--verbose" | "-v" => cli.log_level = Some(LevelFilter::DEBUG),
+            "--help" | "-h" => command = Some(CliCommand::Help),
+            _ if command.is_some() || arg.starts_with('-') => {
+                return Err(format!("unexpected argument `{}`", arg));
+            }
+            "schema" => command = Some(CliCommand::Schema),
+            "profile" => command = Some(CliCommand::Profile(args.next_if(|arg| !arg.starts_with('-')))),
+            "explain" => command = Some(CliCommand::Explain(args.next_if(|arg| !arg.starts_with('-')))),
+            "reset" => command = Some(CliCommand::Reset),
+            "resume" => command = Some(CliCommand::Resume),
+            "help" => command = Some(CliCommand::Help),
+            other => return Err(format!("unknown command `{}`", other)),
+        }
+    }
+    if let Some(command) = command {
+        cli.command = command;
+    }
+    Ok(cli)
+}
+
+/// Implements `kester profile`. A running kester picks the change up from the config file.
+pub fn run_profile(file: &ConfigFile, name: Option<&str>) -> Result<String, String> {
+    let Some(name) = name else {
+        // Listing doesn't change anything, so an old file isn't migrated on disk
+        let (config, _) = config::read_config(file).map_err(|e| e.to_string())?;
+        if config.profiles.is_empty() {
+            return Ok(format!("No profiles defined in {}", file.path));
+        }
+        let active = config.active_profile.as_deref();
+        let list = std::iter::once(("default", active.is_none()))
+            .chain(config.profiles.keys().map(|name| (name.as_str(), active == Some(name.as_str()))))
+            .map(|(name, is_active)| format!("{} {}", if is_active { "*" } else { " " }, name))
+            .collect::<Vec<_>>()
+            .join("\n");
+        return Ok(list);
+    };
+
+    let mut config = config::load_config(file).map_err(|e| e.to_string())?;
+
+    // `default` means the top-level settings, unless a profile is actually called that
+    let target = if name == "default" && !config.profiles.contains_key(name) { None } else { Some(name) };
+    config.set_active_profile(target)?;
+    config::save_config(&mut config, file).map_err(|e| e.to_string())?;
+    Ok(format!("Switched to profile {}", name))
+}
+
+/// Pauses or resumes rule application for `kester reset` and `kester resume`. A running kester
+/// picks the change up from the config file.
+pub fn run_pause(file: &ConfigFile, paused: bool) -> Result<String, String> {
+    let mut config = config::load_config(file).map_err(|e| e.to_string())?;
+    config.paused = paused;
+    config::save_config(&mut config, file).map_err(|e| e.to_string())?;
+    Ok(if paused {
+        "Rules are paused, run `kester resume` to apply them again".to_string()
+    } else {
+        "Rules are applied again".to_string()
+    })
+}
+
+/// Implements `kester explain`.
+pub fn run_explain(file: &ConfigFile, query: Option<&str>) -> Result<String, String> {
+    let (config, _) = config::read_config(file).map_err(|e| e.to_string())?;
+    let source = |rule: &WindowConfig| rule.source.clone().unwrap_or_else(|| file.path.clone());
+    let mut out = String::new();
+
+    if let Some(query) = query {
+        match config.find_rule(query, query) {
+            Some(rule) => write!(out, "`{}` matches {} from {}", query, describe_rule(rule), source(rule)),
+            None => match config.effective_default_opacity() {
+                Some(opacity) => write!(out, "No rule matches `{}`, the default opacity of {}% applies", query, opacity),
+                None => write!(out, "No rule matches `{}` and no default opacity is set, it is left untouched", query),
+            },
+        }.unwrap();
+        return Ok(out);
+    }
+
+    writeln!(out, "Config layers, lowest precedence first:").unwrap();
+    for (i, layer) in config.layers.iter().enumerate() {
+        writeln!(out, "  {}. {}", i + 1, layer.path).unwrap();
+    }
+    let profile = config.active_profile.as_ref()
+        .map(|name| format!(" (profile {})", name))
+        .unwrap_or_default();
+    writeln!(out, "  {}. {}{}", config.layers.len() + 1, file.path, profile).unwrap();
+
+    writeln!(out, "\nRules in match order:").unwrap();
+    for rule in config.effective_rules() {
+        writeln!(out, "  {}  [{}]", describe_rule(rule), source(rule)).unwrap();
+    }
+
+    let default_source = if config.default_opacity().is_some() {
+        Some(file.path.as_str())
+    } else {
+        config.layers.iter().rev().find(|layer| layer.default_opacity.is_some()).map(|layer| layer.path.as_str())
+    };
+    match (config.effective_default_opacity(), default_source) {
+        (Some(opacity), Some(source)) => write!(out, "\nDefault opacity: {}%  [{}]", opacity, source).unwrap(),
+        _ => write!(out, "\nDefault opacity: not set").unwrap(),
+    }
+    Ok(out)
+}
+
+fn describe_rule(rule: &WindowConfig) -> String {
+    let matchers: Vec<String> = [("title", &rule.title), ("executable", &rule.executable)].iter()
+        .filter_map(|(key, value)| value.as_ref().map(|value| format!("{} \"{}\"", key, value)))
+        .collect();
+    let disabled = if rule.enabled { "" } else { " (disabled)" };
+    format!("{} -> {}%{}", matchers.join(" or "), rule.opacity, disabled)
+}
+
+/// Release builds use the Windows GUI subsystem and have no console of their own.
+/// Attach to the terminal kester was started from so command output is visible.
+pub fn attach_parent_console() {
+    unsafe {
+        let _ = windows::Win32::System::Console::AttachConsole(windows::Win32::System::Console::ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn command_arguments_stop_at_flags() {
+        let cli = parse(&["profile", "--config", "x.yaml"]).unwrap();
+        assert_eq!(cli.command, CliCommand::Profile(None));
+        assert_eq!(cli.config_path.as_deref(), Some("x.yaml"));
+
+        let cli = parse(&["explain", "-v"]).unwrap();
+        assert_eq!(cli.command, CliCommand::Explain(None));
+        assert_eq!(cli.log_level, Some(LevelFilter::DEBUG));
+
+        let cli = parse(&["profile", "work", "--config", "x.yaml"]).unwrap();
+        assert_eq!(cli.command, CliCommand::Profile(Some("work".to_string())));
+    }
+}
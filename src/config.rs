@@ -1,41 +1,1595 @@
-use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use serde::ser::Error;
+use crate::error::KesterError;
 
-#[derive(Debug, Deserialize, Serialize)]
+mod edit;
+
+/// Opacity rule for windows matching a title and/or executable. At least one matcher must be set.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 pub struct WindowConfig {
+    /// Matches windows whose title contains this text.
     pub title: Option<String>,
+    /// Matches windows whose executable name contains this text, e.g. `chrome.exe`.
     pub executable: Option<String>,
+    /// Opacity in percent, from 0 (invisible) to 100 (fully opaque).
+    #[schemars(range(max = 100))]
     pub opacity: u8,
+    /// Set to `false` to keep the rule around without applying it.
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub enabled: bool,
+    /// File the rule was read from, for rules that come from an included or system-wide file.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub source: Option<String>,
+}
+
+impl WindowConfig {
+    /// Whether the title or the executable name contains the rule's text, ignoring `enabled`.
+    pub fn matches(&self, title: &str, exe_name: &str) -> bool {
+        self.title.as_ref().is_some_and(|t| title.contains(t.as_str())) ||
+            self.executable.as_ref().is_some_and(|e| exe_name.contains(e.as_str()))
+    }
+
+    /// Whether this is the rule saved for exactly this window, naming both its title and its
+    /// executable, as opposed to a broader rule that merely matches it.
+    pub fn is_for_window(&self, title: &str, exe_name: &str) -> bool {
+        self.title.as_deref() == Some(title) && self.executable.as_deref() == Some(exe_name)
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Kester configuration file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 pub struct Config {
+    /// Schema version of this file. Older versions are migrated automatically.
+    // Keep the range in sync with CONFIG_VERSION
+    #[schemars(range(min = 1, max = 1))]
+    pub version: u32,
+    /// Files to layer underneath this one, relative to this file. Globs such as `conf.d/*.yaml` are
+    /// expanded in alphabetical order. Settings in this file take precedence over included ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Opacity in percent applied to every window without a matching rule. Omit to leave them untouched.
+    #[schemars(range(max = 100))]
+    pub default_opacity: Option<u8>,
+    /// Per-window rules. The first matching rule wins.
+    #[serde(default)]
+    pub specific_windows: Vec<WindowConfig>,
+    /// Named rule sets, e.g. `coding` or `presenting`. The active profile is used instead of the
+    /// top-level `default_opacity` and `specific_windows`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+    /// Name of the profile in use. Omit to use the top-level settings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_profile: Option<String>,
+    /// Put windows back to the opacity they had before kester changed them when kester exits, when
+    /// their rule is removed or when `default_opacity` is turned off. Set to `false` to leave them as they are.
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub restore_original_opacity: bool,
+    /// Stop applying rules and the default opacity, e.g. after the panic reset made every window opaque.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub paused: bool,
+    /// Lowest opacity in percent the sliders go down to, so a slip can't make a window invisible.
+    #[serde(default = "default_min_opacity", skip_serializing_if = "is_default_min_opacity")]
+    #[schemars(range(max = 100))]
+    pub min_opacity: u8,
+    /// System-wide keyboard shortcuts.
+    #[serde(default, skip_serializing_if = "Hotkeys::is_default")]
+    pub hotkeys: Hotkeys,
+    /// Change the opacity of the window under the cursor by turning the mouse wheel while holding a modifier.
+    #[serde(default, skip_serializing_if = "MouseWheel::is_default")]
+    pub mouse_wheel: MouseWheel,
+    /// Settings from the system-wide config and included files, lowest precedence first.
+    /// They are never written back.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub layers: Vec<Layer>,
+    /// Files and include directories the loaded settings came from, for change detection.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub watched_paths: Vec<String>,
+    /// Contents of the file when it was loaded or last saved, so a save doesn't overwrite an edit
+    /// made by another program in the meantime. `None` if the file didn't exist.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub file_contents: Option<String>,
+}
+
+/// Read-only settings contributed by the system-wide config or an `include`d file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub path: String,
+    pub default_opacity: Option<u8>,
+    pub rules: Vec<WindowConfig>,
+}
+
+/// Named set of opacity settings that can be switched to as a whole.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct Profile {
+    /// Opacity in percent applied to every window without a matching rule. Omit to leave them untouched.
+    #[schemars(range(max = 100))]
     pub default_opacity: Option<u8>,
+    /// Per-window rules. The first matching rule wins.
+    #[serde(default)]
     pub specific_windows: Vec<WindowConfig>,
 }
 
+/// System-wide keyboard shortcuts, written like `Ctrl+Alt+Shift+O`. Set one to `none` to turn it off.
+/// The `step_*`, `toggle` and `persist` actions work on the window that has the focus.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct Hotkeys {
+    /// Make every window fully opaque and pause rule application.
+    #[serde(serialize_with = "serialize_hotkey", deserialize_with = "deserialize_hotkey")]
+    pub reset: Option<String>,
+    /// Make the focused window more opaque by `step` percent.
+    #[serde(serialize_with = "serialize_hotkey", deserialize_with = "deserialize_hotkey")]
+    pub step_up: Option<String>,
+    /// Make the focused window more transparent by `step` percent.
+    #[serde(serialize_with = "serialize_hotkey", deserialize_with = "deserialize_hotkey")]
+    pub step_down: Option<String>,
+    /// Switch the focused window between fully opaque and the opacity its rule gives it.
+    #[serde(serialize_with = "serialize_hotkey", deserialize_with = "deserialize_hotkey")]
+    pub toggle: Option<String>,
+    /// Save the focused window's current opacity as a rule.
+    #[serde(serialize_with = "serialize_hotkey", deserialize_with = "deserialize_hotkey")]
+    pub persist: Option<String>,
+    /// Percent to change the opacity by with `step_up` and `step_down`.
+    #[schemars(range(min = 1, max = 100))]
+    pub step: u8,
+}
+
+impl Hotkeys {
+    fn is_default(&self) -> bool {
+        *self == Hotkeys::default()
+    }
+}
+
+/// Written in place of a shortcut to turn a hotkey off. TOML has no `null`, so a hotkey that is
+/// off must still be written out, or it would come back as the default.
+const HOTKEY_OFF: &str = "none";
+
+/// Whether a hotkey setting turns the hotkey off. `null` is read that way too, and so is an empty string.
+fn is_hotkey_off(text: &str) -> bool {
+    text.is_empty() || text.eq_ignore_ascii_case(HOTKEY_OFF)
+}
+
+fn serialize_hotkey<S: Serializer>(hotkey: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(hotkey.as_deref().unwrap_or(HOTKEY_OFF))
+}
+
+fn deserialize_hotkey<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.filter(|text| !is_hotkey_off(text)))
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        Hotkeys {
+            reset: Some("Ctrl+Alt+Shift+O".to_string()),
+            step_up: Some("Ctrl+Alt+Shift+Up".to_string()),
+            step_down: Some("Ctrl+Alt+Shift+Down".to_string()),
+            toggle: Some("Ctrl+Alt+Shift+T".to_string()),
+            persist: Some("Ctrl+Alt+Shift+P".to_string()),
+            step: 10,
+        }
+    }
+}
+
+/// Modifier + mouse wheel opacity changes for the window under the cursor.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct MouseWheel {
+    /// Turn wheel adjustments on.
+    pub enabled: bool,
+    /// Modifiers to hold while turning the wheel, e.g. `Alt` or `Ctrl+Shift`.
+    pub modifier: String,
+    /// Percent to change the opacity by per wheel notch.
+    #[schemars(range(min = 1, max = 100))]
+    pub step: u8,
+    /// Save the new opacity as a rule for the window, like dragging its slider with Persist checked.
+    pub persist: bool,
+}
+
+impl MouseWheel {
+    fn is_default(&self) -> bool {
+        *self == MouseWheel::default()
+    }
+}
+
+impl Default for MouseWheel {
+    fn default() -> Self {
+        MouseWheel { enabled: false, modifier: "Alt".to_string(), step: 5, persist: false }
+    }
+}
+
+const DEFAULT_MIN_OPACITY: u8 = 10;
+
+fn default_min_opacity() -> u8 {
+    DEFAULT_MIN_OPACITY
+}
+
+fn is_default_min_opacity(value: &u8) -> bool {
+    *value == DEFAULT_MIN_OPACITY
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
 impl Default for Config {
     fn default() -> Self {
         Config {
+            version: CONFIG_VERSION,
             default_opacity: None,
             specific_windows: Vec::new(),
+            profiles: BTreeMap::new(),
+            active_profile: None,
+            restore_original_opacity: true,
+            paused: false,
+            min_opacity: DEFAULT_MIN_OPACITY,
+            hotkeys: Hotkeys::default(),
+            mouse_wheel: MouseWheel::default(),
+            include: Vec::new(),
+            layers: Vec::new(),
+            watched_paths: Vec::new(),
+            file_contents: None,
+        }
+    }
+}
+
+impl Config {
+    fn active(&self) -> Option<&Profile> {
+        self.active_profile.as_ref().and_then(|name| self.profiles.get(name))
+    }
+
+    /// Default opacity of the active profile, or the top-level one when no profile is active.
+    pub fn default_opacity(&self) -> Option<u8> {
+        match self.active() {
+            Some(profile) => profile.default_opacity,
+            None => self.default_opacity,
+        }
+    }
+
+    pub fn set_default_opacity(&mut self, opacity: Option<u8>) {
+        match self.active_profile.as_ref().and_then(|name| self.profiles.get_mut(name)) {
+            Some(profile) => profile.default_opacity = opacity,
+            None => self.default_opacity = opacity,
+        }
+    }
+
+    /// Rules of the active profile, or the top-level rules when no profile is active.
+    pub fn rules(&self) -> &Vec<WindowConfig> {
+        match self.active() {
+            Some(profile) => &profile.specific_windows,
+            None => &self.specific_windows,
+        }
+    }
+
+    pub fn rules_mut(&mut self) -> &mut Vec<WindowConfig> {
+        match self.active_profile.as_ref().and_then(|name| self.profiles.get_mut(name)) {
+            Some(profile) => &mut profile.specific_windows,
+            None => &mut self.specific_windows,
+        }
+    }
+
+    /// Every rule in match order: this file's (active) rules first, then each layer from the
+    /// highest precedence down to the system-wide baseline.
+    pub fn effective_rules(&self) -> impl Iterator<Item = &WindowConfig> {
+        self.rules().iter().chain(self.layers.iter().rev().flat_map(|layer| layer.rules.iter()))
+    }
+
+    /// Default opacity from this file, falling back to the highest layer that sets one.
+    pub fn effective_default_opacity(&self) -> Option<u8> {
+        self.default_opacity()
+            .or_else(|| self.layers.iter().rev().find_map(|layer| layer.default_opacity))
+    }
+
+    /// First enabled rule matching a window, in `effective_rules` order.
+    pub fn find_rule(&self, title: &str, exe_name: &str) -> Option<&WindowConfig> {
+        self.find_rule_index(title, exe_name).map(|(_, rule)| rule)
+    }
+
+    /// Like `find_rule`, along with the rule's position in `effective_rules`.
+    pub fn find_rule_index(&self, title: &str, exe_name: &str) -> Option<(usize, &WindowConfig)> {
+        self.effective_rules().enumerate().find(|(_, rule)| rule.enabled && rule.matches(title, exe_name))
+    }
+
+    /// Switches to the named profile, or back to the top-level settings with `None`.
+    pub fn set_active_profile(&mut self, name: Option<&str>) -> Result<(), String> {
+        match name {
+            Some(name) if !self.profiles.contains_key(name) => Err(format!("no profile named `{}`", name)),
+            _ => {
+                self.active_profile = name.map(String::from);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Schema version written by this build. Bump it and append to `MIGRATIONS` when the layout changes.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// `MIGRATIONS[n]` upgrades a version `n` document to version `n + 1`.
+const MIGRATIONS: &[fn(Value) -> Value] = &[migrate_v0_to_v1];
+
+const CONFIG_KEYS: &[&str] = &["version", "default_opacity", "specific_windows", "profiles", "active_profile", "include", "restore_original_opacity", "paused", "min_opacity", "hotkeys", "mouse_wheel"];
+const PROFILE_KEYS: &[&str] = &["default_opacity", "specific_windows"];
+const RULE_KEYS: &[&str] = &["title", "executable", "opacity", "enabled"];
+const MOUSE_WHEEL_KEYS: &[&str] = &["enabled", "modifier", "step", "persist"];
+const HOTKEY_ACTIONS: &[&str] = &["reset", "step_up", "step_down", "toggle", "persist"];
+const HOTKEY_KEYS: &[&str] = &["reset", "step_up", "step_down", "toggle", "persist", "step"];
+
+/// A single problem found in a config file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    /// 1-based line number, when it could be located.
+    pub line: Option<usize>,
+    /// Profile the problem belongs to, if it isn't in the top-level rule set.
+    pub profile: Option<String>,
+    /// Index into `specific_windows`, when the problem belongs to a rule.
+    pub rule: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.file)?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        match (&self.profile, self.rule) {
+            (Some(profile), Some(rule)) => write!(f, ": profiles.{}.specific_windows[{}]", profile, rule)?,
+            (Some(profile), None) => write!(f, ": profiles.{}", profile)?,
+            (None, Some(rule)) => write!(f, ": specific_windows[{}]", rule)?,
+            (None, None) => {}
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Every problem found while loading a config file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigErrors(pub Vec<Diagnostic>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl ConfigErrors {
+    fn single(path: &str, line: Option<usize>, message: String) -> Self {
+        ConfigErrors(vec![Diagnostic { file: path.to_string(), line, profile: None, rule: None, message }])
+    }
+}
+
+/// Serialization format of a config file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Format implied by the file extension. Anything unrecognised is treated as YAML.
+    pub fn from_path(path: &str) -> Self {
+        let extension = Path::new(path).extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("toml") => ConfigFormat::Toml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            "toml" => Some(ConfigFormat::Toml),
+            "json" => Some(ConfigFormat::Json),
+            _ => None,
+        }
+    }
+
+    fn parse(self, contents: &str) -> Result<Value, (Option<usize>, String)> {
+        match self {
+            ConfigFormat::Yaml => serde_yaml::from_str(contents)
+                .map_err(|e| (e.location().map(|l| l.line()), e.to_string())),
+            ConfigFormat::Toml => toml::from_str(contents).map_err(|e| {
+                let line = e.span().map(|span| contents[..span.start].lines().count().max(1));
+                (line, e.message().to_string())
+            }),
+            // An empty JSON file is treated like an empty YAML file rather than a syntax error
+            ConfigFormat::Json if contents.trim().is_empty() => Ok(Value::Null),
+            ConfigFormat::Json => serde_json::from_str(contents)
+                .map_err(|e| (Some(e.line()), e.to_string())),
+        }
+    }
+
+    fn serialize(self, config: &Config) -> Result<String, serde_yaml::Error> {
+        match self {
+            ConfigFormat::Yaml => serde_yaml::to_string(config),
+            ConfigFormat::Toml => toml::to_string_pretty(config).map_err(serde_yaml::Error::custom),
+            ConfigFormat::Json => serde_json::to_string_pretty(config)
+                .map(|json| json + "\n")
+                .map_err(serde_yaml::Error::custom),
+        }
+    }
+}
+
+/// Location and format of the config file kester reads and writes back to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigFile {
+    pub path: String,
+    pub format: ConfigFormat,
+}
+
+impl ConfigFile {
+    /// Uses `format` if given, otherwise detects it from the file extension.
+    pub fn new(path: &str, format: Option<ConfigFormat>) -> Self {
+        ConfigFile {
+            path: path.to_string(),
+            format: format.unwrap_or_else(|| ConfigFormat::from_path(path)),
         }
     }
+
+    /// `config.yaml` in the working directory, or `config.toml`/`config.json` if only one of those exists.
+    pub fn default_path() -> String {
+        ["config.yaml", "config.yml", "config.toml", "config.json"].iter()
+            .find(|path| Path::new(path).exists())
+            .unwrap_or(&"config.yaml")
+            .to_string()
+    }
+}
+
+/// JSON Schema for the config file, for editor completion and validation.
+pub fn json_schema() -> String {
+    let schema = schemars::schema_for!(Config);
+    serde_json::to_string_pretty(&schema).expect("Config schema serializes to JSON")
+}
+
+/// Loads the config, upgrading files written for an older schema version.
+///
+/// A migrated file is backed up next to the original (`config.yaml.v0.bak`) before it is rewritten
+/// in its original format.
+pub fn load_config(file: &ConfigFile) -> Result<Config, ConfigErrors> {
+    let path = file.path.as_str();
+    let (mut config, file_version) = read_config(file)?;
+
+    if file_version < CONFIG_VERSION {
+        let backup = backup_path(path, file_version);
+        fs::copy(path, &backup).map_err(|e| ConfigErrors::single(
+            path, None, format!("failed to back up file to {} before migrating it: {}", backup, e)
+        ))?;
+        save_config(&mut config, file).map_err(|e| ConfigErrors::single(
+            path, None, format!("failed to write migrated config: {}", e)
+        ))?;
+    }
+    Ok(config)
 }
 
-pub fn load_config(path: &str) -> Result<Config, serde_yaml::Error> {
+/// Loads the config without changing the file, for commands that only look at it. Files written
+/// for an older schema version are upgraded in memory. Returns the config along with the schema
+/// version the file was written in.
+pub fn read_config(file: &ConfigFile) -> Result<(Config, u32), ConfigErrors> {
+    let path = file.path.as_str();
     if !Path::new(path).exists() {
-        return Ok(Config::default());
+        // The system-wide baseline still applies without a user config
+        let mut config = Config::default();
+        load_layers(&mut config, path)?;
+        return Ok((config, CONFIG_VERSION));
     }
-    let contents = fs::read_to_string(path).unwrap_or_default();
-    serde_yaml::from_str(&contents)
+    let contents = fs::read_to_string(path)
+        .map_err(|e| ConfigErrors::single(path, None, format!("failed to read file: {}", e)))?;
+    let (mut config, file_version) = parse_config(path, file.format, &contents)?;
+    load_layers(&mut config, path)?;
+    config.file_contents = Some(contents);
+    Ok((config, file_version))
+}
+
+/// Machine-wide baseline config, e.g. `C:\ProgramData\kester\config.yaml`, if one is installed.
+pub fn system_config_path() -> Option<String> {
+    let dir = PathBuf::from(std::env::var_os("ProgramData")?).join("kester");
+    ["config.yaml", "config.yml", "config.toml", "config.json"].iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.exists())
+        .map(|path| path.to_string_lossy().into_owned())
 }
 
-pub fn save_config(config: &Config, path: &str) -> Result<(), serde_yaml::Error> {
-    let yaml = serde_yaml::to_string(config)?;
-    fs::write(path, yaml).map_err(|_| serde_yaml::Error::custom("Failed to write config file"))?;
+/// Loads the layers underneath the main config at `path`.
+///
+/// Merge order, lowest precedence first:
+/// 1. the system-wide config, followed by the files it includes
+/// 2. the files the main config includes, in the order listed (glob matches alphabetically)
+/// 3. the main config itself
+///
+/// Each included file is itself layered underneath the file that includes it. Rules are matched
+/// from the highest layer down, and `default_opacity` comes from the highest layer that sets it.
+fn load_layers(config: &mut Config, path: &str) -> Result<(), ConfigErrors> {
+    let mut loader = LayerLoader::default();
+    loader.visited.insert(canonical(path));
+    loader.watched.push(path.to_string());
+
+    if let Some(system) = system_config_path() {
+        if loader.visited.insert(canonical(&system)) {
+            loader.load_file(&system, 0);
+        }
+    }
+    loader.load_includes(path, &config.include, 0);
+
+    if !loader.diagnostics.is_empty() {
+        return Err(ConfigErrors(loader.diagnostics));
+    }
+    config.layers = loader.layers;
+    config.watched_paths = loader.watched;
     Ok(())
 }
 
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+#[derive(Default)]
+struct LayerLoader {
+    layers: Vec<Layer>,
+    diagnostics: Vec<Diagnostic>,
+    visited: HashSet<PathBuf>,
+    watched: Vec<String>,
+}
+
+impl LayerLoader {
+    fn load_includes(&mut self, from: &str, patterns: &[String], depth: usize) {
+        let base = Path::new(from).parent().unwrap_or(Path::new(""));
+        for pattern in patterns {
+            let full = base.join(pattern).to_string_lossy().into_owned();
+            let paths = match glob::glob(&full) {
+                Ok(paths) => paths,
+                Err(e) => {
+                    self.error(from, format!("invalid include pattern `{}`: {}", pattern, e));
+                    continue;
+                }
+            };
+
+            let is_glob = pattern.contains(['*', '?', '[']);
+            if is_glob {
+                // Watch the directory so files added to it are picked up too
+                if let Some(dir) = Path::new(&full).parent() {
+                    self.watched.push(dir.to_string_lossy().into_owned());
+                }
+            }
+
+            let mut matches: Vec<PathBuf> = paths.filter_map(Result::ok).collect();
+            matches.sort();
+            if matches.is_empty() && !is_glob {
+                self.error(from, format!("included file `{}` does not exist", pattern));
+            }
+            for path in matches {
+                let path = path.to_string_lossy().into_owned();
+                if !self.visited.insert(canonical(&path)) {
+                    self.error(from, format!("`{}` is included more than once or includes itself", path));
+                    continue;
+                }
+                self.load_file(&path, depth + 1);
+            }
+        }
+    }
+
+    fn load_file(&mut self, path: &str, depth: usize) {
+        if depth > MAX_INCLUDE_DEPTH {
+            self.error(path, format!("includes are nested more than {} levels deep", MAX_INCLUDE_DEPTH));
+            return;
+        }
+        self.watched.push(path.to_string());
+
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => return self.error(path, format!("failed to read file: {}", e)),
+        };
+        let config = match parse_config(path, ConfigFormat::from_path(path), &contents) {
+            Ok((config, _)) => config,
+            Err(errors) => return self.diagnostics.extend(errors.0),
+        };
+        if !config.profiles.is_empty() || config.active_profile.is_some() {
+            self.error(path, "`profiles` and `active_profile` are only supported in the main config file".to_string());
+        }
+
+        // The file's own includes sit underneath it
+        self.load_includes(path, &config.include, depth);
+
+        let rules = config.specific_windows.into_iter()
+            .map(|rule| WindowConfig { source: Some(path.to_string()), ..rule })
+            .collect();
+        self.layers.push(Layer { path: path.to_string(), default_opacity: config.default_opacity, rules });
+    }
+
+    fn error(&mut self, path: &str, message: String) {
+        self.diagnostics.extend(ConfigErrors::single(path, None, message).0);
+    }
+}
+
+fn canonical(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+/// Parses, migrates and validates config file contents, collecting every problem instead of stopping
+/// at the first. Returns the config along with the schema version the contents were written in.
+pub fn parse_config(path: &str, format: ConfigFormat, contents: &str) -> Result<(Config, u32), ConfigErrors> {
+    let value = format.parse(contents)
+        .map_err(|(line, message)| ConfigErrors::single(path, line, message))?;
+
+    // Key and rule line numbers are only tracked for YAML
+    let contents = if format == ConfigFormat::Yaml { contents } else { "" };
+
+    // A missing, empty or fully commented-out file means "no settings"
+    if value.is_null() {
+        return Ok((Config::default(), CONFIG_VERSION));
+    }
+
+    let file_version = schema_version(&value)
+        .map_err(|message| ConfigErrors::single(path, LineIndex::new(contents).key(&["version"]), message))?;
+    // Line numbers can't be mapped back once a bare list of rules has been wrapped in a mapping
+    let index_source = if value.is_sequence() { "" } else { contents };
+    let value = migrate(value, file_version);
+
+    let diagnostics = validate(path, index_source, &value);
+    if !diagnostics.is_empty() {
+        return Err(ConfigErrors(diagnostics));
+    }
+
+    let config = serde_yaml::from_value(value)
+        .map_err(|e| ConfigErrors::single(path, None, e.to_string()))?;
+    Ok((config, file_version))
+}
+
+/// Version of a parsed document. Files without a `version` key predate versioning and count as version 0.
+fn schema_version(value: &Value) -> Result<u32, String> {
+    let Some(version) = value.as_mapping().and_then(|root| root.get("version")) else {
+        return Ok(0);
+    };
+    match version.as_u64() {
+        Some(version) if version <= CONFIG_VERSION as u64 => Ok(version as u32),
+        Some(version) => Err(format!(
+            "config version {} was written by a newer kester, this build understands up to version {}",
+            version, CONFIG_VERSION
+        )),
+        None => Err(format!("version must be a whole number, got `{}`", display_value(version))),
+    }
+}
+
+fn migrate(mut value: Value, from: u32) -> Value {
+    for migration in &MIGRATIONS[from as usize..] {
+        value = migration(value);
+    }
+    value
+}
+
+/// Version 0 is every file written before versioning, including a bare list of rules
+/// without the `specific_windows` key.
+fn migrate_v0_to_v1(value: Value) -> Value {
+    let mut root = match value {
+        Value::Sequence(rules) => {
+            let mut root = Mapping::new();
+            root.insert("specific_windows".into(), Value::Sequence(rules));
+            root
+        }
+        Value::Mapping(root) => root,
+        // Leave anything else for validation to report
+        other => return other,
+    };
+    root.insert("version".into(), 1.into());
+    Value::Mapping(root)
+}
+
+fn backup_path(path: &str, version: u32) -> String {
+    let mut backup = format!("{}.v{}.bak", path, version);
+    let mut n = 1;
+    while Path::new(&backup).exists() {
+        backup = format!("{}.v{}.{}.bak", path, version, n);
+        n += 1;
+    }
+    backup
+}
+
+/// Checks a config built in memory, such as rules edited in the GUI, the way a file is checked
+/// when it is loaded. The diagnostics have no line numbers.
+pub fn check_config(path: &str, config: &Config) -> Vec<Diagnostic> {
+    match serde_yaml::to_value(config) {
+        Ok(value) => validate(path, "", &value),
+        Err(e) => ConfigErrors::single(path, None, e.to_string()).0,
+    }
+}
+
+fn validate(path: &str, contents: &str, value: &Value) -> Vec<Diagnostic> {
+    let mut validator = Validator { path, lines: LineIndex::new(contents), diagnostics: Vec::new() };
+    validator.config(value);
+    validator.diagnostics
+}
+
+/// Walks a parsed document and records every problem, with line numbers where they can be found.
+struct Validator<'a> {
+    path: &'a str,
+    lines: LineIndex<'a>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Validator<'_> {
+    fn report(&mut self, line: Option<usize>, profile: Option<&str>, rule: Option<usize>, message: String) {
+        self.diagnostics.push(Diagnostic {
+            file: self.path.to_string(),
+            line,
+            profile: profile.map(String::from),
+            rule,
+            message,
+        });
+    }
+
+    fn config(&mut self, value: &Value) {
+        let Some(root) = value.as_mapping() else {
+            let line = (!self.lines.lines.is_empty()).then_some(1);
+            self.report(line, None, None, "expected a mapping with `version`, `default_opacity` and `specific_windows`".to_string());
+            return;
+        };
+
+        for key in unknown_keys(root, CONFIG_KEYS) {
+            let line = self.lines.key(&[&key]);
+            self.report(line, None, None, format!("unknown key `{}`", key));
+        }
+
+        self.rule_set(root, &[], None);
+
+        let profiles = match root.get("profiles") {
+            None | Some(Value::Null) => None,
+            Some(Value::Mapping(profiles)) => Some(profiles),
+            Some(_) => {
+                let line = self.lines.key(&["profiles"]);
+                self.report(line, None, None, "profiles must be a mapping of profile names to rule sets".to_string());
+                None
+            }
+        };
+
+        for (name, profile) in profiles.into_iter().flatten() {
+            let Some(name) = name.as_str() else {
+                let line = self.lines.key(&["profiles"]);
+                self.report(line, None, None, format!("profile names must be strings, got `{}`", display_value(name)));
+                continue;
+            };
+            let path = ["profiles", name];
+            match profile {
+                Value::Mapping(profile) => {
+                    for key in unknown_keys(profile, PROFILE_KEYS) {
+                        let line = self.lines.key(&["profiles", name, &key]);
+                        self.report(line, Some(name), None, format!("unknown key `{}`", key));
+                    }
+                    self.rule_set(profile, &path, Some(name));
+                }
+                // An empty profile is allowed, it just leaves every window untouched
+                Value::Null => {}
+                _ => {
+                    let line = self.lines.key(&path);
+                    self.report(line, Some(name), None, "expected a mapping with `default_opacity` and `specific_windows`".to_string());
+                }
+            }
+        }
+
+        match root.get("include") {
+            None | Some(Value::Null) => {}
+            Some(Value::Sequence(patterns)) if patterns.iter().all(Value::is_string) => {}
+            Some(_) => {
+                let line = self.lines.key(&["include"]);
+                self.report(line, None, None, "include must be a list of file paths or globs".to_string());
+            }
+        }
+
+        match root.get("active_profile") {
+            None | Some(Value::Null) => {}
+            Some(Value::String(active)) => {
+                if !profiles.is_some_and(|profiles| profiles.contains_key(active.as_str())) {
+                    let line = self.lines.key(&["active_profile"]);
+                    self.report(line, None, None, format!("active_profile `{}` is not defined under `profiles`", active));
+                }
+            }
+            Some(other) => {
+                let line = self.lines.key(&["active_profile"]);
+                self.report(line, None, None, format!("active_profile must be a profile name, got `{}`", display_value(other)));
+            }
+        }
+
+        for key in ["restore_original_opacity", "paused"] {
+            match root.get(key) {
+                None | Some(Value::Bool(_)) => {}
+                Some(other) => {
+                    let line = self.lines.key(&[key]);
+                    self.report(line, None, None, format!("{} must be true or false, got `{}`", key, display_value(other)));
+                }
+            }
+        }
+
+        if let Some(opacity) = root.get("min_opacity") {
+            if let Some(message) = check_opacity(opacity) {
+                let line = self.lines.key(&["min_opacity"]);
+                self.report(line, None, None, format!("min_opacity {}", message));
+            }
+        }
+
+        match root.get("mouse_wheel") {
+            None | Some(Value::Null) => {}
+            Some(Value::Mapping(mouse_wheel)) => self.mouse_wheel(mouse_wheel),
+            Some(_) => {
+                let line = self.lines.key(&["mouse_wheel"]);
+                self.report(line, None, None, "mouse_wheel must be a mapping with `enabled`, `modifier`, `step` and `persist`".to_string());
+            }
+        }
+
+        match root.get("hotkeys") {
+            None | Some(Value::Null) => {}
+            Some(Value::Mapping(hotkeys)) => self.hotkeys(hotkeys),
+            Some(_) => {
+                let line = self.lines.key(&["hotkeys"]);
+                self.report(line, None, None, "hotkeys must be a mapping of actions to shortcuts like `Ctrl+Alt+O`".to_string());
+            }
+        }
+    }
+
+    fn mouse_wheel(&mut self, mouse_wheel: &Mapping) {
+        for key in unknown_keys(mouse_wheel, MOUSE_WHEEL_KEYS) {
+            let line = self.lines.key(&["mouse_wheel", &key]);
+            self.report(line, None, None, format!("unknown key `{}`", key));
+        }
+
+        for key in ["enabled", "persist"] {
+            match mouse_wheel.get(key) {
+                None | Some(Value::Bool(_)) => {}
+                Some(other) => {
+                    let line = self.lines.key(&["mouse_wheel", key]);
+                    self.report(line, None, None, format!("mouse_wheel.{} must be true or false, got `{}`", key, display_value(other)));
+                }
+            }
+        }
+
+        if let Some(step) = mouse_wheel.get("step") {
+            if !step.as_u64().is_some_and(|step| (1..=100).contains(&step)) {
+                let line = self.lines.key(&["mouse_wheel", "step"]);
+                self.report(line, None, None, format!("mouse_wheel.step must be a whole number between 1 and 100, got `{}`", display_value(step)));
+            }
+        }
+
+        let line = self.lines.key(&["mouse_wheel", "modifier"]);
+        match mouse_wheel.get("modifier") {
+            None => {}
+            // Without a modifier every scroll would change opacity instead of scrolling
+            Some(Value::String(text)) => match crate::hotkey::parse_modifiers(text) {
+                Ok(_) => {}
+                Err(message) => self.report(line, None, None, format!("mouse_wheel.modifier: {}", message)),
+            },
+            Some(other) => {
+                self.report(line, None, None, format!("mouse_wheel.modifier must be modifiers like `Alt` or `Ctrl+Shift`, got `{}`", display_value(other)));
+            }
+        }
+    }
+
+    fn hotkeys(&mut self, hotkeys: &Mapping) {
+        for key in unknown_keys(hotkeys, HOTKEY_KEYS) {
+            let line = self.lines.key(&["hotkeys", &key]);
+            self.report(line, None, None, format!("unknown hotkey action `{}`", key));
+        }
+
+        if let Some(step) = hotkeys.get("step") {
+            if !step.as_u64().is_some_and(|step| (1..=100).contains(&step)) {
+                let line = self.lines.key(&["hotkeys", "step"]);
+                self.report(line, None, None, format!("hotkeys.step must be a whole number between 1 and 100, got `{}`", display_value(step)));
+            }
+        }
+
+        let mut seen: Vec<(crate::hotkey::Hotkey, &str)> = Vec::new();
+        for action in HOTKEY_ACTIONS {
+            let line = self.lines.key(&["hotkeys", action]);
+            match hotkeys.get(*action) {
+                None | Some(Value::Null) => {}
+                Some(Value::String(text)) if is_hotkey_off(text) => {}
+                Some(Value::String(text)) => match crate::hotkey::Hotkey::parse(text) {
+                    Ok(hotkey) => {
+                        let same = seen.iter().find(|(other, _)| (other.modifiers, other.key) == (hotkey.modifiers, hotkey.key));
+                        if let Some((_, other_action)) = same {
+                            self.report(line, None, None, format!("hotkeys.{}: {} is already used for {}", action, text, other_action));
+                        }
+                        seen.push((hotkey, action));
+                    }
+                    Err(message) => self.report(line, None, None, format!("hotkeys.{}: {}", action, message)),
+                },
+                Some(other) => {
+                    self.report(line, None, None, format!("hotkeys.{} must be a shortcut like `Ctrl+Alt+O` or `none`, got `{}`", action, display_value(other)));
+                }
+            }
+        }
+    }
+
+    /// Validates `default_opacity` and `specific_windows` of the top level or of a profile at `path`.
+    fn rule_set(&mut self, mapping: &Mapping, path: &[&str], profile: Option<&str>) {
+        let key_path = |key: &'static str| -> Vec<&str> {
+            path.iter().copied().chain([key]).collect()
+        };
+
+        if let Some(opacity) = mapping.get("default_opacity") {
+            if !opacity.is_null() {
+                if let Some(message) = check_opacity(opacity) {
+                    let line = self.lines.key(&key_path("default_opacity"));
+                    self.report(line, profile, None, format!("default_opacity {}", message));
+                }
+            }
+        }
+
+        let rules_path = key_path("specific_windows");
+        let rules = match mapping.get("specific_windows") {
+            None | Some(Value::Null) => return,
+            Some(Value::Sequence(rules)) => rules,
+            Some(_) => {
+                let line = self.lines.key(&rules_path);
+                self.report(line, profile, None, "specific_windows must be a list of rules".to_string());
+                return;
+            }
+        };
+
+        let items = self.lines.sequence_items(&rules_path);
+        let mut seen: Vec<(usize, Option<&str>, Option<&str>)> = Vec::new();
+        for (index, rule) in rules.iter().enumerate() {
+            let item = items.get(index).copied();
+            let rule_line = item.map(|(start, _)| start + 1);
+            let key_line = |lines: &LineIndex, key: &str| {
+                item.and_then(|(start, end)| lines.key_in_range(start, end, key)).or(rule_line)
+            };
+
+            let Some(rule) = rule.as_mapping() else {
+                self.report(rule_line, profile, Some(index), "expected a mapping with `title`, `executable` and `opacity`".to_string());
+                continue;
+            };
+
+            for key in unknown_keys(rule, RULE_KEYS) {
+                let line = key_line(&self.lines, &key);
+                self.report(line, profile, Some(index), format!("unknown key `{}`", key));
+            }
+
+            match rule.get("opacity") {
+                None => self.report(rule_line, profile, Some(index), "missing `opacity`".to_string()),
+                Some(opacity) => {
+                    if let Some(message) = check_opacity(opacity) {
+                        let line = key_line(&self.lines, "opacity");
+                        self.report(line, profile, Some(index), format!("opacity {}", message));
+                    }
+                }
+            }
+
+            if let Some(enabled) = rule.get("enabled").filter(|enabled| !enabled.is_bool()) {
+                let line = key_line(&self.lines, "enabled");
+                self.report(line, profile, Some(index), format!("enabled must be true or false, got `{}`", display_value(enabled)));
+            }
+
+            let matcher = |validator: &mut Self, key: &str| -> Option<&str> {
+                match rule.get(key) {
+                    None | Some(Value::Null) => None,
+                    Some(Value::String(s)) => Some(s.as_str()).filter(|s| !s.is_empty()),
+                    Some(_) => {
+                        let line = key_line(&validator.lines, key);
+                        validator.report(line, profile, Some(index), format!("`{}` must be a string", key));
+                        None
+                    }
+                }
+            };
+            let title = matcher(self, "title");
+            let executable = matcher(self, "executable");
+
+            if title.is_none() && executable.is_none() {
+                self.report(rule_line, profile, Some(index), "rule has neither `title` nor `executable`, so it can never match".to_string());
+                continue;
+            }
+
+            // A disabled rule is never used anyway, and doesn't hide the rules after it
+            if matches!(rule.get("enabled"), Some(Value::Bool(false))) {
+                continue;
+            }
+            if let Some((first, _, _)) = seen.iter().find(|(_, t, e)| *t == title && *e == executable) {
+                let message = format!("duplicate of specific_windows[{}], this rule is never used", first);
+                self.report(rule_line, profile, Some(index), message);
+            } else {
+                seen.push((index, title, executable));
+            }
+        }
+    }
+}
+
+fn unknown_keys(mapping: &Mapping, known: &[&str]) -> Vec<String> {
+    mapping.keys()
+        .map(|key| key.as_str().map(String::from).unwrap_or_else(|| format!("{:?}", key)))
+        .filter(|key| !known.contains(&key.as_str()))
+        .collect()
+}
+
+fn check_opacity(value: &Value) -> Option<String> {
+    match value.as_u64() {
+        Some(opacity) if opacity <= 100 => None,
+        Some(opacity) => Some(format!("{} is out of range (0-100)", opacity)),
+        None => Some(format!("must be a whole number between 0 and 100, got `{}`", display_value(value))),
+    }
+}
+
+fn display_value(value: &Value) -> String {
+    serde_yaml::to_string(value).map(|s| s.trim_end().to_string()).unwrap_or_default()
+}
+
+/// Best-effort mapping from config keys and rules back to line numbers for diagnostics.
+///
+/// Only understands block-style YAML, which is all kester itself writes. Internally lines are
+/// 0-based; the public lookups return 1-based line numbers.
+struct LineIndex<'a> {
+    lines: Vec<&'a str>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(contents: &'a str) -> Self {
+        LineIndex { lines: contents.lines().collect() }
+    }
+
+    fn is_content(line: &str) -> bool {
+        let trimmed = line.trim_start();
+        !trimmed.is_empty() && !trimmed.starts_with('#')
+    }
+
+    fn indent(line: &str) -> usize {
+        line.len() - line.trim_start().len()
+    }
+
+    fn is_item(line: &str) -> bool {
+        line.trim_start().starts_with('-')
+    }
+
+    fn starts_key(line: &str, key: &str) -> bool {
+        let trimmed = line.trim_start().trim_start_matches("- ");
+        trimmed.strip_prefix(key).is_some_and(|rest| rest.trim_start().starts_with(':'))
+    }
+
+    /// End (exclusive) of the block nested under the key on `line`.
+    fn block_end(&self, line: usize) -> usize {
+        let indent = Self::indent(self.lines[line]);
+        (line + 1..self.lines.len())
+            .find(|&i| {
+                let l = self.lines[i];
+                // A list may sit at the same indentation as its key
+                Self::is_content(l) && (Self::indent(l) < indent || (Self::indent(l) == indent && !Self::is_item(l)))
+            })
+            .unwrap_or(self.lines.len())
+    }
+
+    /// 0-based line of the key at `path`, e.g. `["profiles", "coding", "specific_windows"]`.
+    fn find(&self, path: &[&str]) -> Option<usize> {
+        let (mut start, mut end) = (0, self.lines.len());
+        let mut found = None;
+        for key in path {
+            let child_indent = (start..end)
+                .find(|&i| Self::is_content(self.lines[i]))
+                .map(|i| Self::indent(self.lines[i]))?;
+            let line = (start..end).find(|&i| {
+                Self::indent(self.lines[i]) == child_indent && Self::starts_key(self.lines[i], key)
+            })?;
+            found = Some(line);
+            start = line + 1;
+            end = self.block_end(line);
+        }
+        found
+    }
+
+    /// 1-based line of the key at `path`.
+    fn key(&self, path: &[&str]) -> Option<usize> {
+        self.find(path).map(|line| line + 1)
+    }
+
+    /// 0-based line ranges of each `- ` item of the list under the key at `path`.
+    fn sequence_items(&self, path: &[&str]) -> Vec<(usize, usize)> {
+        let Some(key_line) = self.find(path) else {
+            return Vec::new();
+        };
+        let end = self.block_end(key_line);
+        let mut starts = Vec::new();
+        let mut item_indent = None;
+        for i in key_line + 1..end {
+            let line = self.lines[i];
+            if Self::is_content(line) && Self::is_item(line) && *item_indent.get_or_insert(Self::indent(line)) == Self::indent(line) {
+                starts.push(i);
+            }
+        }
+        starts.iter().enumerate()
+            .map(|(n, &start)| (start, starts.get(n + 1).copied().unwrap_or(end)))
+            .collect()
+    }
+
+    /// 1-based line of `key:` within the 0-based line range `start..end`.
+    fn key_in_range(&self, start: usize, end: usize, key: &str) -> Option<usize> {
+        (start..end)
+            .find(|&i| Self::starts_key(self.lines[i], key))
+            .map(|i| i + 1)
+    }
+}
+
+/// Number of rolling backups (`config.yaml.bak.1` is the newest) kept next to the config file.
+const BACKUP_COUNT: usize = 5;
+
+/// Backups are rotated at most this often, so dragging a slider doesn't push every useful backup out.
+const BACKUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Writes the config back in the file's own format.
+///
+/// The write is crash-safe: the new contents go to a temporary file that is then renamed over the
+/// config, so the file is never left half-written. A lock file serializes writers across kester
+/// instances, and the previous contents are kept in rolling backups. If another program changed the
+/// file since `config` was loaded, nothing is written and `KesterError::ConfigStale` is returned, so
+/// the outside edit can be reloaded instead of being overwritten.
+///
+/// YAML files are updated in place where possible, keeping comments and key order; other formats
+/// are rewritten from scratch.
+pub fn save_config(config: &mut Config, file: &ConfigFile) -> Result<(), KesterError> {
+    let io_error = |action: &str, e: std::io::Error| {
+        KesterError::ConfigIo { path: file.path.clone(), message: format!("failed to {} the file: {}", action, e) }
+    };
+
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(format!("{}.lock", file.path))
+        .map_err(|e| io_error("lock", e))?;
+    lock.lock().map_err(|e| io_error("lock", e))?;
+
+    // Checked under the lock, so another kester instance can't write in between
+    let current = fs::read_to_string(&file.path).ok();
+    if current != config.file_contents {
+        return Err(KesterError::ConfigStale { path: file.path.clone() });
+    }
+
+    let edited = match (file.format, &current) {
+        (ConfigFormat::Yaml, Some(current)) => edit::update_document(&file.path, current, config),
+        _ => None,
+    };
+    let contents = match edited {
+        Some(contents) => contents,
+        None => file.format.serialize(config)
+            .map_err(|e| KesterError::ConfigParse { path: file.path.clone(), message: e.to_string() })?,
+    };
+
+    rotate_backups(&file.path).map_err(|e| io_error("back up", e))?;
+    write_atomically(&file.path, contents.as_bytes()).map_err(|e| io_error("write", e))?;
+    config.file_contents = Some(contents);
+
+    // Dropping the handle releases the lock
+    drop(lock);
+    Ok(())
+}
+
+fn rotate_backups(path: &str) -> std::io::Result<()> {
+    if !Path::new(path).exists() {
+        return Ok(());
+    }
+    let backup = |n: usize| format!("{}.bak.{}", path, n);
+
+    let newest_is_recent = fs::metadata(backup(1))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < BACKUP_INTERVAL);
+    if newest_is_recent {
+        return Ok(());
+    }
+
+    for n in (1..BACKUP_COUNT).rev() {
+        if Path::new(&backup(n)).exists() {
+            fs::rename(backup(n), backup(n + 1))?;
+        }
+    }
+    // Copy by hand so the backup's modification time is the time it was taken
+    fs::write(backup(1), fs::read(path)?)
+}
+
+fn write_atomically(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let temp = format!("{}.tmp-{}", path, std::process::id());
+    let result = (|| {
+        let mut temp_file = fs::File::create(&temp)?;
+        temp_file.write_all(contents)?;
+        // Make sure the data is on disk before the rename makes it visible
+        temp_file.sync_all()?;
+        fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Polls the config files and calls `on_change` whenever the modification time or size of any of them
+/// changes. `paths` can be updated while watching, e.g. when the set of included files changes.
+///
+/// Polling is used instead of filesystem notifications because editors and dotfile sync tools
+/// usually replace the file (write + rename), which breaks watches on the original inode.
+pub fn watch_config<F>(paths: Arc<Mutex<Vec<String>>>, on_change: F)
+where
+    F: Fn() + Send + 'static,
+{
+    thread::spawn(move || {
+        let stamp = || -> Vec<Option<(SystemTime, u64)>> {
+            paths.lock().unwrap().iter()
+                .map(|path| {
+                    let metadata = fs::metadata(path).ok()?;
+                    Some((metadata.modified().ok()?, metadata.len()))
+                })
+                .collect()
+        };
+
+        let mut last = stamp();
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            let current = stamp();
+            if current != last {
+                last = current;
+                on_change();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES_WITH_PROFILE: &str = "\
+version: 1
+profiles:
+  work:
+    specific_windows:
+      - executable: slack.exe
+        opacity: 70
+active_profile: work
+";
+
+    /// An empty directory of its own for a test that works with files.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kester-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn parse(contents: &str) -> Result<(Config, u32), ConfigErrors> {
+        parse_config("test.yaml", ConfigFormat::Yaml, contents)
+    }
+
+    fn diagnostics(contents: &str) -> Vec<(Option<usize>, Option<usize>, String)> {
+        parse(contents).unwrap_err().0.into_iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.rule, diagnostic.message))
+            .collect()
+    }
+
+    #[test]
+    fn reports_every_problem_with_its_line() {
+        let contents = "\
+version: 1
+default_opacity: 120
+colour: blue
+specific_windows:
+  - title: Notepad
+    opacity: 80
+  - opacity: 50
+  - executable: chrome.exe
+    opacity: high
+    enabled: maybe
+  - title: Notepad
+    opacity: 60
+";
+        assert_eq!(diagnostics(contents), vec![
+            (Some(3), None, "unknown key `colour`".to_string()),
+            (Some(2), None, "default_opacity 120 is out of range (0-100)".to_string()),
+            (Some(7), Some(1), "rule has neither `title` nor `executable`, so it can never match".to_string()),
+            (Some(9), Some(2), "opacity must be a whole number between 0 and 100, got `high`".to_string()),
+            (Some(10), Some(2), "enabled must be true or false, got `maybe`".to_string()),
+            (Some(11), Some(3), "duplicate of specific_windows[0], this rule is never used".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn locates_problems_in_profiles() {
+        let contents = "\
+version: 1
+profiles:
+  work:
+    specific_windows:
+      - title: Slack
+        opacity: 101
+        colour: blue
+active_profile: home
+";
+        let errors = parse(contents).unwrap_err();
+        assert_eq!(errors.to_string(), "\
+test.yaml:7: profiles.work.specific_windows[0]: unknown key `colour`
+test.yaml:6: profiles.work.specific_windows[0]: opacity 101 is out of range (0-100)
+test.yaml:8: active_profile `home` is not defined under `profiles`");
+    }
+
+    #[test]
+    fn reports_problems_in_other_formats_without_lines() {
+        let contents = "version = 1\n\n[[specific_windows]]\ntitle = \"\"\nopacity = 250\n";
+        let errors = parse_config("test.toml", ConfigFormat::Toml, contents).unwrap_err();
+        assert_eq!(errors.to_string(), "\
+test.toml: specific_windows[0]: opacity 250 is out of range (0-100)
+test.toml: specific_windows[0]: rule has neither `title` nor `executable`, so it can never match");
+    }
+
+    #[test]
+    fn only_enabled_rules_shadow_their_copies() {
+        let rules = |first: bool, second: bool| format!(
+            "version: 1\nspecific_windows:\n  - title: Notepad\n    opacity: 80\n    enabled: {}\n  - title: Notepad\n    opacity: 60\n    enabled: {}\n",
+            first, second,
+        );
+        assert!(parse(&rules(false, true)).is_ok());
+        assert!(parse(&rules(true, false)).is_ok());
+        assert_eq!(diagnostics(&rules(true, true)), vec![
+            (Some(6), Some(1), "duplicate of specific_windows[0], this rule is never used".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn checks_edited_rules_like_a_loaded_file() {
+        let (mut config, _) = parse(RULES_WITH_PROFILE).unwrap();
+        assert_eq!(check_config("test.yaml", &config), Vec::new());
+
+        let copy = config.rules()[0].clone();
+        config.rules_mut().push(copy);
+        let diagnostics: Vec<_> = check_config("test.yaml", &config).into_iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.profile, diagnostic.rule, diagnostic.message))
+            .collect();
+        assert_eq!(diagnostics, vec![(
+            None,
+            Some("work".to_string()),
+            Some(1),
+            "duplicate of specific_windows[0], this rule is never used".to_string(),
+        )]);
+    }
+
+    #[test]
+    fn migrates_a_bare_list_of_rules() {
+        let (config, version) = parse("- title: Notepad\n  opacity: 80\n").unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.specific_windows.len(), 1);
+        assert_eq!(config.specific_windows[0].title.as_deref(), Some("Notepad"));
+        assert_eq!(config.specific_windows[0].opacity, 80);
+    }
+
+    #[test]
+    fn rejects_a_newer_version() {
+        assert_eq!(diagnostics("version: 99\n"), vec![(
+            Some(1),
+            None,
+            format!("config version 99 was written by a newer kester, this build understands up to version {}", CONFIG_VERSION),
+        )]);
+    }
+
+    #[test]
+    fn backs_up_a_file_before_migrating_it() {
+        let dir = temp_dir("migrate");
+        let path = dir.join("config.yaml");
+        let original = "- title: Notepad\n  opacity: 80\n";
+        fs::write(&path, original).unwrap();
+
+        let config = load_config(&ConfigFile::new(path.to_str().unwrap(), None)).unwrap();
+        assert_eq!(config.specific_windows.len(), 1);
+        assert_eq!(fs::read_to_string(dir.join("config.yaml.v0.bak")).unwrap(), original);
+        let (migrated, version) = parse(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(version, CONFIG_VERSION);
+        assert_eq!(migrated.specific_windows, config.specific_windows);
+    }
+
+    #[test]
+    fn reads_an_old_file_without_rewriting_it() {
+        let dir = temp_dir("read");
+        let path = dir.join("config.yaml");
+        let original = "- title: Notepad\n  opacity: 80\n";
+        fs::write(&path, original).unwrap();
+
+        let (config, version) = read_config(&ConfigFile::new(path.to_str().unwrap(), None)).unwrap();
+        assert_eq!((config.version, version), (CONFIG_VERSION, 0));
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+        assert!(!dir.join("config.yaml.v0.bak").exists());
+    }
+
+    /// Writes `files` (name and contents) to `dir` and loads `config.yaml` from it.
+    fn load_files(dir: &Path, files: &[(&str, &str)]) -> Result<Config, ConfigErrors> {
+        for (name, contents) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        load_config(&ConfigFile::new(dir.join("config.yaml").to_str().unwrap(), None))
+    }
+
+    #[test]
+    fn layers_includes_underneath_the_main_file() {
+        let dir = temp_dir("layers");
+        let config = load_files(&dir, &[
+            ("config.yaml", "version: 1\ninclude: [base.yaml, conf.d/*.yaml]\nspecific_windows:\n  - title: Mine\n    opacity: 90\n"),
+            ("base.yaml", "version: 1\ndefault_opacity: 95\nspecific_windows:\n  - title: Shared\n    opacity: 10\n"),
+            ("conf.d/20-team.yaml", "version: 1\nspecific_windows:\n  - title: Shared\n    opacity: 30\n"),
+            ("conf.d/10-site.yaml", "version: 1\ndefault_opacity: 85\nspecific_windows:\n  - title: Mine\n    opacity: 20\n"),
+            ("conf.d/notes.txt", "not a config"),
+        ]).unwrap();
+
+        let names: Vec<&str> = config.layers.iter()
+            .map(|layer| Path::new(&layer.path).file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["base.yaml", "10-site.yaml", "20-team.yaml"]);
+
+        assert_eq!(config.find_rule("Mine", "").map(|rule| rule.opacity), Some(90));
+        let shared = config.find_rule("Shared", "").unwrap();
+        assert_eq!(shared.opacity, 30);
+        assert!(shared.source.as_deref().unwrap().ends_with("20-team.yaml"));
+        assert_eq!(config.effective_default_opacity(), Some(85));
+    }
+
+    #[test]
+    fn rejects_an_include_cycle() {
+        let dir = temp_dir("cycle");
+        let errors = load_files(&dir, &[
+            ("config.yaml", "version: 1\ninclude: [a.yaml]\n"),
+            ("a.yaml", "version: 1\ninclude: [b.yaml]\n"),
+            ("b.yaml", "version: 1\ninclude: [a.yaml]\n"),
+        ]).unwrap_err();
+        assert_eq!(errors.0.len(), 1);
+        assert!(errors.0[0].file.ends_with("b.yaml"));
+        assert_eq!(errors.0[0].message, format!("`{}` is included more than once or includes itself", dir.join("a.yaml").display()));
+    }
+
+    #[test]
+    fn rejects_includes_nested_too_deep() {
+        let dir = temp_dir("depth");
+        let files: Vec<(String, String)> = (0..=MAX_INCLUDE_DEPTH + 1)
+            .map(|n| {
+                let name = if n == 0 { "config.yaml".to_string() } else { format!("{}.yaml", n) };
+                (name, format!("version: 1\ninclude: [{}.yaml]\n", n + 1))
+            })
+            .collect();
+        // The last file's include is the one that doesn't exist, but nesting stops before it
+        let files: Vec<(&str, &str)> = files.iter().map(|(name, contents)| (name.as_str(), contents.as_str())).collect();
+        let errors = load_files(&dir, &files).unwrap_err();
+        assert_eq!(errors.0.len(), 1);
+        assert!(errors.0[0].file.ends_with(&format!("{}.yaml", MAX_INCLUDE_DEPTH + 1)));
+        assert_eq!(errors.0[0].message, format!("includes are nested more than {} levels deep", MAX_INCLUDE_DEPTH));
+    }
+
+    #[test]
+    fn rotates_backups_and_keeps_a_limited_number() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("config.yaml");
+        let path = path.to_str().unwrap();
+        let backup = |n: usize| format!("{}.bak.{}", path, n);
+        for n in 1..=BACKUP_COUNT {
+            fs::write(backup(n), n.to_string()).unwrap();
+        }
+        let old = SystemTime::now() - BACKUP_INTERVAL * 2;
+        fs::File::options().write(true).open(backup(1)).unwrap().set_modified(old).unwrap();
+        fs::write(path, "current").unwrap();
+
+        rotate_backups(path).unwrap();
+        let contents: Vec<String> = (1..=BACKUP_COUNT).map(|n| fs::read_to_string(backup(n)).unwrap()).collect();
+        assert_eq!(contents, ["current", "1", "2", "3", "4"]);
+        assert!(!Path::new(&backup(BACKUP_COUNT + 1)).exists());
+    }
+
+    #[test]
+    fn backs_up_at_most_once_per_interval() {
+        let dir = temp_dir("interval");
+        let path = dir.join("config.yaml");
+        let path = path.to_str().unwrap();
+        fs::write(path, "first").unwrap();
+        rotate_backups(path).unwrap();
+        fs::write(path, "second").unwrap();
+        rotate_backups(path).unwrap();
+
+        assert_eq!(fs::read_to_string(format!("{}.bak.1", path)).unwrap(), "first");
+        assert!(!Path::new(&format!("{}.bak.2", path)).exists());
+    }
+
+    #[test]
+    fn writes_through_a_temporary_file() {
+        let dir = temp_dir("atomic");
+        let leftovers = || fs::read_dir(&dir).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().contains(".tmp-"))
+            .count();
+
+        let path = dir.join("config.yaml");
+        fs::write(&path, "old").unwrap();
+        write_atomically(path.to_str().unwrap(), b"new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(leftovers(), 0);
+
+        // A directory can't be replaced by a file, so the rename fails
+        let blocked = dir.join("blocked");
+        fs::create_dir(&blocked).unwrap();
+        fs::write(blocked.join("keep"), "").unwrap();
+        assert!(write_atomically(blocked.to_str().unwrap(), b"new").is_err());
+        assert_eq!(leftovers(), 0);
+    }
+
+    #[test]
+    fn refuses_to_overwrite_an_outside_edit() {
+        let dir = temp_dir("stale");
+        let mut config = load_files(&dir, &[("config.yaml", "version: 1\ndefault_opacity: 80\n")]).unwrap();
+        let file = ConfigFile::new(dir.join("config.yaml").to_str().unwrap(), None);
+
+        config.default_opacity = Some(70);
+        save_config(&mut config, &file).unwrap();
+        // Our own save doesn't count as an outside edit
+        config.default_opacity = Some(60);
+        save_config(&mut config, &file).unwrap();
+
+        let edited = "version: 1\ndefault_opacity: 50 # by hand\n";
+        fs::write(&file.path, edited).unwrap();
+        config.default_opacity = Some(40);
+        assert_eq!(save_config(&mut config, &file), Err(KesterError::ConfigStale { path: file.path.clone() }));
+        assert_eq!(fs::read_to_string(&file.path).unwrap(), edited);
+    }
+
+    #[test]
+    fn keeps_hotkeys_off_in_every_format() {
+        let mut config = Config::default();
+        config.hotkeys.toggle = None;
+        for format in [ConfigFormat::Yaml, ConfigFormat::Toml, ConfigFormat::Json] {
+            let contents = format.serialize(&config).unwrap();
+            let (parsed, _) = parse_config("test", format, &contents).unwrap();
+            assert_eq!(parsed, config, "{:?}", format);
+        }
+
+        let (parsed, _) = parse("version: 1\nhotkeys:\n  reset: null\n  toggle: None\n  persist: ''\n").unwrap();
+        assert_eq!((parsed.hotkeys.reset, parsed.hotkeys.toggle, parsed.hotkeys.persist), (None, None, None));
+    }
+
+    #[test]
+    fn finds_nested_keys_and_list_items() {
+        let lines = LineIndex::new("\
+version: 1
+profiles:
+  work:
+    # Chat
+    specific_windows:
+    - title: Slack
+      opacity: 70
+
+    - executable: code.exe
+      opacity: 90
+");
+        assert_eq!(lines.key(&["profiles", "work", "specific_windows"]), Some(5));
+        assert_eq!(lines.key(&["profiles", "home"]), None);
+        assert_eq!(lines.sequence_items(&["profiles", "work", "specific_windows"]), vec![(5, 8), (8, 10)]);
+        assert_eq!(lines.key_in_range(8, 10, "opacity"), Some(10));
+    }
+}
@@ -0,0 +1,512 @@
+//! In-place updates of a YAML config file.
+//!
+//! Saving from the GUI only rewrites the keys and rules that actually changed, so the user's
+//! comments, blank lines and key order survive. Anything the editor doesn't understand makes it
+//! give up, and the caller falls back to serializing the whole file.
+
+use super::{parse_config, Config, ConfigFormat, LineIndex, WindowConfig};
+use serde::de::DeserializeOwned;
+use serde_yaml::Value;
+
+/// Root keys in the order kester writes them, used to place keys that are missing from the file.
+const ROOT_ORDER: &[&str] = &["version", "include", "default_opacity", "specific_windows", "profiles", "active_profile", "restore_original_opacity", "paused", "min_opacity", "hotkeys"];
+const PROFILE_ORDER: &[&str] = &["default_opacity", "specific_windows"];
+
+/// Replaces the 0-based line range `start..end` with `lines`. An empty range is an insertion.
+struct Edit {
+    start: usize,
+    end: usize,
+    lines: Vec<String>,
+}
+
+/// The parts of a `key: value  # comment` line.
+struct KeyLine<'a> {
+    /// Everything up to and including the colon, e.g. `  - title:`.
+    head: &'a str,
+    value: &'a str,
+    /// Whitespace and comment after the value.
+    tail: &'a str,
+}
+
+impl KeyLine<'_> {
+    fn with_value(&self, value: &str) -> String {
+        if value.is_empty() {
+            format!("{}{}", self.head, self.tail)
+        } else {
+            format!("{} {}{}", self.head, value, self.tail)
+        }
+    }
+
+    /// Whether the value is a scalar written on the key's own line.
+    fn is_inline_scalar(&self) -> bool {
+        self.value.chars().next().is_some_and(|c| !"|>&*![{".contains(c))
+    }
+}
+
+/// Returns `contents` updated to hold `config`, or `None` if it can't be done in place.
+///
+/// The result is parsed back and compared with `config` before it is returned, so a wrong edit
+/// can only cost the comments, never the settings.
+pub(super) fn update_document(path: &str, contents: &str, config: &Config) -> Option<String> {
+    let old: Value = serde_yaml::from_str(contents).ok()?;
+    let old = if old.is_null() { Value::Mapping(Default::default()) } else { old };
+    old.as_mapping()?;
+
+    // The GUI never changes includes or the set of profiles
+    if decode::<Vec<String>>(lookup(&old, &["include"]))? != config.include {
+        return None;
+    }
+    let old_profiles: Vec<&str> = match lookup(&old, &["profiles"]) {
+        Some(profiles) => profiles.as_mapping()?.keys().map(Value::as_str).collect::<Option<_>>()?,
+        None => Vec::new(),
+    };
+    if old_profiles.len() != config.profiles.len() || old_profiles.iter().any(|name| !config.profiles.contains_key(*name)) {
+        return None;
+    }
+
+    let mut editor = Editor { index: LineIndex::new(contents), old: &old, edits: Vec::new() };
+    editor.scalar(&["version"], Some(Value::from(config.version)), ROOT_ORDER)?;
+    editor.scalar(&["default_opacity"], config.default_opacity.map(Value::from), ROOT_ORDER)?;
+    editor.rules(&["specific_windows"], &config.specific_windows, ROOT_ORDER)?;
+    for (name, profile) in &config.profiles {
+        editor.scalar(&["profiles", name, "default_opacity"], profile.default_opacity.map(Value::from), PROFILE_ORDER)?;
+        editor.rules(&["profiles", name, "specific_windows"], &profile.specific_windows, PROFILE_ORDER)?;
+    }
+    editor.scalar(&["active_profile"], config.active_profile.clone().map(Value::from), ROOT_ORDER)?;
+    // Keep an explicit `paused: false` rather than dropping the key
+    let paused = (config.paused || lookup(&old, &["paused"]).is_some()).then_some(Value::Bool(config.paused));
+    editor.scalar(&["paused"], paused, ROOT_ORDER)?;
+
+    let newline = if contents.contains("\r\n") { "\r\n" } else { "\n" };
+    let updated = editor.apply(newline);
+
+    let (parsed, _) = parse_config(path, ConfigFormat::Yaml, &updated).ok()?;
+    (serde_yaml::to_value(&parsed).ok()? == serde_yaml::to_value(config).ok()?).then_some(updated)
+}
+
+struct Editor<'a> {
+    index: LineIndex<'a>,
+    old: &'a Value,
+    edits: Vec<Edit>,
+}
+
+impl Editor<'_> {
+    fn replace(&mut self, start: usize, end: usize, lines: Vec<String>) {
+        self.edits.push(Edit { start, end, lines });
+    }
+
+    fn line(&self, line: usize) -> &str {
+        self.index.lines[line]
+    }
+
+    /// End (exclusive) of the last non-comment line in `start..end`, or `start` if there is none.
+    fn content_end(&self, start: usize, end: usize) -> usize {
+        (start..end).rev()
+            .find(|&i| LineIndex::is_content(self.line(i)))
+            .map_or(start, |i| i + 1)
+    }
+
+    /// Sets the scalar at `path`, removing the key for `None`.
+    fn scalar(&mut self, path: &[&str], value: Option<Value>, order: &[&str]) -> Option<()> {
+        if lookup(self.old, path) == value.as_ref() {
+            return Some(());
+        }
+        let key = path[path.len() - 1];
+        match (self.index.find(path), value) {
+            (Some(line), Some(value)) => {
+                let key_line = split_key_line(self.line(line), key)?;
+                if !key_line.is_inline_scalar() {
+                    return None;
+                }
+                let updated = key_line.with_value(&render(&value)?);
+                self.replace(line, line + 1, vec![updated]);
+            }
+            (Some(line), None) => {
+                if !split_key_line(self.line(line), key)?.is_inline_scalar() {
+                    return None;
+                }
+                self.replace(line, line + 1, Vec::new());
+            }
+            (None, Some(value)) => self.insert_key(path, vec![format!("{}: {}", key, render(&value)?)], order)?,
+            (None, None) => return None,
+        }
+        Some(())
+    }
+
+    /// Adds the key at `path`, written as `lines` relative to its parent's indentation.
+    ///
+    /// The key goes in front of the first sibling that comes after it in `order`, together with any
+    /// comment lines directly above that sibling, or else at the end of the parent.
+    fn insert_key(&mut self, path: &[&str], lines: Vec<String>, order: &[&str]) -> Option<()> {
+        let (parent, key) = path.split_at(path.len() - 1);
+        let (start, end, indent) = if parent.is_empty() {
+            (0, self.index.lines.len(), 0)
+        } else {
+            let line = self.index.find(parent)?;
+            let end = self.index.block_end(line);
+            let indent = (line + 1..end)
+                .find(|&i| LineIndex::is_content(self.line(i)))
+                .map_or(LineIndex::indent(self.line(line)) + 2, |i| LineIndex::indent(self.line(i)));
+            (line + 1, end, indent)
+        };
+
+        let later = order.iter().skip_while(|k| **k != key[0]).skip(1);
+        let sibling = later
+            .filter_map(|k| self.index.find(&[parent, &[*k]].concat()))
+            .min();
+        let at = match sibling {
+            Some(mut at) => {
+                while at > start && self.line(at - 1).trim_start().starts_with('#') {
+                    at -= 1;
+                }
+                at
+            }
+            None => self.content_end(start, end),
+        };
+
+        let pad = " ".repeat(indent);
+        self.replace(at, at, lines.into_iter().map(|l| format!("{}{}", pad, l)).collect());
+        Some(())
+    }
+
+    /// Updates the rule list at `path`, touching only the rules that were added, removed or changed.
+    fn rules(&mut self, path: &[&str], new: &[WindowConfig], order: &[&str]) -> Option<()> {
+        let old: Vec<WindowConfig> = decode(lookup(self.old, path))?;
+        if old == new {
+            return Some(());
+        }
+        let key = path[path.len() - 1];
+
+        let Some(key_line) = self.index.find(path) else {
+            if !old.is_empty() {
+                return None;
+            }
+            let mut lines = vec![format!("{}:", key)];
+            for rule in new {
+                lines.extend(render_rule(rule, 2)?);
+            }
+            return self.insert_key(path, lines, order);
+        };
+
+        let items = self.index.sequence_items(path);
+        let item_indent = items.first()
+            .map_or(LineIndex::indent(self.line(key_line)) + 2, |&(start, _)| LineIndex::indent(self.line(start)));
+
+        // Going from or to an empty list, or a list the line index can't follow: rewrite the key's block
+        if old.is_empty() || new.is_empty() || items.len() != old.len() {
+            let end = self.content_end(key_line + 1, self.index.block_end(key_line)).max(key_line + 1);
+            let head = split_key_line(self.line(key_line), key)?;
+            let mut lines = vec![head.with_value(if new.is_empty() { "[]" } else { "" })];
+            for rule in new {
+                lines.extend(render_rule(rule, item_indent)?);
+            }
+            self.replace(key_line, end, lines);
+            return Some(());
+        }
+
+        // Content range of each item, leaving comments in front of the next item alone
+        let items: Vec<(usize, usize)> = items.iter()
+            .map(|&(start, end)| (start, self.content_end(start, end)))
+            .collect();
+        let list_end = items[items.len() - 1].1;
+
+        let mut deleted = Vec::new();
+        let mut inserted = Vec::new();
+        for op in diff(&old, new) {
+            match op {
+                Op::Delete(i) => deleted.push(i),
+                Op::Insert(j) => inserted.push(j),
+                Op::Keep(i) => {
+                    self.flush(&mut deleted, &mut inserted, &items, &old, new, items[i].0, item_indent)?;
+                }
+            }
+        }
+        self.flush(&mut deleted, &mut inserted, &items, &old, new, list_end, item_indent)
+    }
+
+    /// Applies a run of removed and added rules between two unchanged ones. Pairs of them are
+    /// treated as edits of the same rule; any extra added rules are inserted at line `at`.
+    #[allow(clippy::too_many_arguments)]
+    fn flush(
+        &mut self,
+        deleted: &mut Vec<usize>,
+        inserted: &mut Vec<usize>,
+        items: &[(usize, usize)],
+        old: &[WindowConfig],
+        new: &[WindowConfig],
+        at: usize,
+        item_indent: usize,
+    ) -> Option<()> {
+        let paired = deleted.len().min(inserted.len());
+        for (&i, &j) in deleted.iter().zip(inserted.iter()) {
+            self.update_rule(items[i], &old[i], &new[j], item_indent)?;
+        }
+        for &i in &deleted[paired..] {
+            // Comments directly above a removed rule go with it
+            let mut start = items[i].0;
+            while start > 0 && self.line(start - 1).trim_start().starts_with('#') {
+                start -= 1;
+            }
+            self.replace(start, items[i].1, Vec::new());
+        }
+        let mut lines = Vec::new();
+        for &j in &inserted[paired..] {
+            lines.extend(render_rule(&new[j], item_indent)?);
+        }
+        if !lines.is_empty() {
+            self.replace(at, at, lines);
+        }
+        deleted.clear();
+        inserted.clear();
+        Some(())
+    }
+
+    /// Updates the fields of the rule on lines `start..end` one by one, or rewrites the whole rule
+    /// if a field can't be edited on its own line.
+    fn update_rule(&mut self, (start, end): (usize, usize), old: &WindowConfig, new: &WindowConfig, item_indent: usize) -> Option<()> {
+        let mut edits = Vec::new();
+        let mut in_place = true;
+        for ((key, old_value), (_, new_value)) in rule_fields(old).into_iter().zip(rule_fields(new)) {
+            if old_value == new_value {
+                continue;
+            }
+            let line = self.index.key_in_range(start, end, key).map(|line| line - 1);
+            let key_line = line.and_then(|line| split_key_line(self.line(line), key));
+            match (line, key_line, new_value) {
+                (Some(line), Some(key_line), Some(value)) if key_line.is_inline_scalar() => {
+                    edits.push(Edit { start: line, end: line + 1, lines: vec![key_line.with_value(&render(&value)?)] });
+                }
+                // The first key shares its line with the `- ` marker, so it can't be dropped alone
+                (Some(line), Some(key_line), None) if key_line.is_inline_scalar() && line != start => {
+                    edits.push(Edit { start: line, end: line + 1, lines: Vec::new() });
+                }
+                (None, _, Some(value)) => {
+                    let line = format!("{}{}: {}", " ".repeat(item_indent + 2), key, render(&value)?);
+                    edits.push(Edit { start: end, end, lines: vec![line] });
+                }
+                _ => in_place = false,
+            }
+        }
+
+        if in_place {
+            self.edits.extend(edits);
+        } else {
+            self.replace(start, end, render_rule(new, item_indent)?);
+        }
+        Some(())
+    }
+
+    fn apply(self, newline: &str) -> String {
+        let mut lines: Vec<String> = self.index.lines.iter().map(|line| line.to_string()).collect();
+        let mut edits: Vec<(usize, Edit)> = self.edits.into_iter().enumerate().collect();
+        // Bottom-up so line numbers stay valid. At the same line a replacement goes first, and
+        // insertions are applied in reverse so they end up in the order they were made.
+        edits.sort_by_key(|(seq, edit)| std::cmp::Reverse((edit.start, edit.start != edit.end, *seq)));
+        for (_, edit) in edits {
+            lines.splice(edit.start..edit.end, edit.lines);
+        }
+        let mut updated = lines.join(newline);
+        updated.push_str(newline);
+        updated
+    }
+}
+
+enum Op {
+    Keep(usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Longest-common-subsequence diff of two rule lists.
+fn diff(old: &[WindowConfig], new: &[WindowConfig]) -> Vec<Op> {
+    let (n, m) = (old.len(), new.len());
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i] == new[j] {
+            ops.push(Op::Keep(i));
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || common[i][j + 1] >= common[i + 1][j]) {
+            ops.push(Op::Insert(j));
+            j += 1;
+        } else {
+            ops.push(Op::Delete(i));
+            i += 1;
+        }
+    }
+    ops
+}
+
+/// Splits a `key: value  # comment` line, optionally starting with a `- ` list marker.
+fn split_key_line<'a>(line: &'a str, key: &str) -> Option<KeyLine<'a>> {
+    let trimmed = line.trim_start();
+    let body = trimmed.strip_prefix('-').map_or(trimmed, str::trim_start);
+    let rest = body.strip_prefix(key)?.trim_start().strip_prefix(':')?;
+    let colon = line.len() - rest.len();
+
+    // A comment starts at a `#` after whitespace, outside of quotes
+    let mut quote = None;
+    let mut comment = rest.len();
+    let bytes = rest.as_bytes();
+    for (i, &c) in bytes.iter().enumerate() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == b'"' || c == b'\'' => quote = Some(c),
+            None if c == b'#' && (i == 0 || bytes[i - 1].is_ascii_whitespace()) => {
+                comment = i;
+                break;
+            }
+            None => {}
+        }
+    }
+
+    let code = &rest[..comment];
+    let value = code.trim();
+    let value_end = if value.is_empty() { 0 } else { code.len() - code.trim_start().len() + value.len() };
+    Some(KeyLine { head: &line[..colon], value, tail: &rest[value_end..] })
+}
+
+/// A scalar as it appears after `key: `, or `None` if it needs more than one line.
+fn render(value: &Value) -> Option<String> {
+    let rendered = serde_yaml::to_string(value).ok()?;
+    let rendered = rendered.trim_end();
+    (!rendered.contains('\n')).then(|| rendered.to_string())
+}
+
+fn rule_fields(rule: &WindowConfig) -> [(&'static str, Option<Value>); 4] {
+    [
+        ("title", rule.title.clone().map(Value::from)),
+        ("executable", rule.executable.clone().map(Value::from)),
+        ("opacity", Some(Value::from(rule.opacity))),
+        ("enabled", (!rule.enabled).then_some(Value::from(false))),
+    ]
+}
+
+/// A rule as a `- ` list item indented by `indent`.
+fn render_rule(rule: &WindowConfig, indent: usize) -> Option<Vec<String>> {
+    let pad = " ".repeat(indent);
+    let mut lines = Vec::new();
+    for (key, value) in rule_fields(rule) {
+        if let Some(value) = value {
+            let marker = if lines.is_empty() { "- " } else { "  " };
+            lines.push(format!("{}{}{}: {}", pad, marker, key, render(&value)?));
+        }
+    }
+    Some(lines)
+}
+
+fn lookup<'v>(value: &'v Value, path: &[&str]) -> Option<&'v Value> {
+    path.iter()
+        .try_fold(value, |value, key| value.get(*key))
+        .filter(|value| !value.is_null())
+}
+
+/// Deserializes an optional value, treating a missing one as the type's default.
+fn decode<T: DeserializeOwned + Default>(value: Option<&Value>) -> Option<T> {
+    value.map_or(Some(T::default()), |value| serde_yaml::from_value(value.clone()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = "\
+version: 1
+# Rules, first match wins
+specific_windows:
+  # Editor
+  - title: Notepad # keep me
+    opacity: 80
+  - executable: chrome.exe
+    opacity: 50
+";
+
+    fn update(contents: &str, change: impl FnOnce(&mut Config)) -> String {
+        let (mut config, _) = parse_config("test.yaml", ConfigFormat::Yaml, contents).unwrap();
+        change(&mut config);
+        update_document("test.yaml", contents, &config).expect("in-place update failed")
+    }
+
+    #[test]
+    fn edits_a_rule_in_place() {
+        let updated = update(RULES, |config| config.rules_mut()[0].opacity = 70);
+        assert_eq!(updated, RULES.replace("opacity: 80", "opacity: 70"));
+    }
+
+    #[test]
+    fn deletes_a_rule_with_its_comment() {
+        let updated = update(RULES, |config| {
+            config.rules_mut().remove(0);
+        });
+        assert_eq!(updated, "\
+version: 1
+# Rules, first match wins
+specific_windows:
+  - executable: chrome.exe
+    opacity: 50
+");
+
+        let updated = update(RULES, |config| {
+            config.rules_mut().remove(1);
+        });
+        assert_eq!(updated, RULES.replace("  - executable: chrome.exe\n    opacity: 50\n", ""));
+    }
+
+    #[test]
+    fn reorders_rules() {
+        let updated = update(RULES, |config| config.rules_mut().swap(0, 1));
+        assert_eq!(updated, "\
+version: 1
+# Rules, first match wins
+specific_windows:
+  # Editor
+  - executable: chrome.exe
+    opacity: 50
+  - title: Notepad # keep me
+    opacity: 80
+");
+    }
+
+    #[test]
+    fn disables_and_enables_a_rule() {
+        let disabled = update(RULES, |config| config.rules_mut()[0].enabled = false);
+        assert_eq!(disabled, RULES.replace("opacity: 80\n", "opacity: 80\n    enabled: false\n"));
+
+        let enabled = update(&disabled, |config| config.rules_mut()[0].enabled = true);
+        assert_eq!(enabled, RULES);
+    }
+
+    #[test]
+    fn inserts_a_missing_key_in_order() {
+        let updated = update(RULES, |config| config.set_default_opacity(Some(90)));
+        assert_eq!(updated, RULES.replace("version: 1\n", "version: 1\ndefault_opacity: 90\n"));
+    }
+
+    #[test]
+    fn keeps_crlf_line_endings() {
+        let contents = RULES.replace('\n', "\r\n");
+        let updated = update(&contents, |config| config.rules_mut()[1].opacity = 40);
+        assert_eq!(updated, contents.replace("opacity: 50", "opacity: 40"));
+    }
+
+    #[test]
+    fn gives_up_when_profiles_change() {
+        let (mut config, _) = parse_config("test.yaml", ConfigFormat::Yaml, RULES).unwrap();
+        config.profiles.insert("work".to_string(), Default::default());
+        assert_eq!(update_document("test.yaml", RULES, &config), None);
+    }
+}
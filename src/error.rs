@@ -0,0 +1,71 @@
+use std::fmt;
+use windows::Win32::Foundation::E_ACCESSDENIED;
+
+/// Something that went wrong while kester was running. The GUI lists these in its status area,
+/// as release builds have no console for `println!` to show up in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KesterError {
+    /// A config file couldn't be locked, backed up or written.
+    ConfigIo { path: String, message: String },
+    /// The config file was changed by another program since it was loaded, so it wasn't saved.
+    ConfigStale { path: String },
+    /// The config couldn't be turned into text, or refers to something it doesn't define.
+    ConfigParse { path: String, message: String },
+    /// Windows refused to let kester change a window, usually one of an app running as administrator.
+    AccessDenied { window: String },
+    /// Changing a window failed for another reason.
+    Window { window: String, message: String },
+    /// The tray icon couldn't be created or rebuilt.
+    Tray(String),
+}
+
+/// Why the last change kester made to a window didn't take. Shown next to the window in the list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApplyFailure {
+    /// `HRESULT` of the call that failed, or 0 if Windows accepted the change but the window
+    /// didn't keep it.
+    pub code: i32,
+    pub reason: String,
+    /// What the user can do about it, if the cause could be told.
+    pub hint: Option<&'static str>,
+}
+
+impl ApplyFailure {
+    /// A failed call, with `hint` as advice.
+    pub fn new(error: &windows::core::Error, hint: Option<&'static str>) -> Self {
+        let reason = match error.message() {
+            message if message.is_empty() => "unknown error".to_string(),
+            message => message,
+        };
+        ApplyFailure { code: error.code().0, reason, hint }
+    }
+
+    /// Whether Windows refused kester access to the window.
+    pub fn is_access_denied(&self) -> bool {
+        self.code == E_ACCESSDENIED.0
+    }
+
+    /// The failure as a status area entry for the window titled `window`.
+    pub fn to_error(&self, window: &str) -> KesterError {
+        if self.is_access_denied() {
+            KesterError::AccessDenied { window: window.to_string() }
+        } else {
+            KesterError::Window { window: window.to_string(), message: self.reason.clone() }
+        }
+    }
+}
+
+impl fmt::Display for KesterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KesterError::ConfigIo { path, message } => write!(f, "Could not save {}: {}", path, message),
+            KesterError::ConfigStale { path } => write!(f, "Not saving {}: it was changed by another program since it was loaded", path),
+            KesterError::ConfigParse { path, message } => write!(f, "Problem with {}: {}", path, message),
+            KesterError::AccessDenied { window } => write!(f, "Access denied changing \"{}\"", window),
+            KesterError::Window { window, message } => write!(f, "Could not change \"{}\": {}", window, message),
+            KesterError::Tray(message) => write!(f, "Tray icon: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for KesterError {}
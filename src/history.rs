@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+/// Oldest snapshots are dropped beyond this many undo steps.
+const MAX_STEPS: usize = 100;
+
+/// Changes of the same kind closer together than this are undone as one step, so dragging a
+/// slider doesn't take one undo per pixel.
+const COALESCE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Undo/redo stacks of snapshots of some state `T`.
+///
+/// `K` identifies what a change touched; consecutive changes with the same key are coalesced.
+pub struct History<T, K> {
+    undo: Vec<T>,
+    redo: Vec<T>,
+    last_change: Option<(K, Instant)>,
+}
+
+impl<T: Clone, K: PartialEq> History<T, K> {
+    pub fn new() -> Self {
+        History { undo: Vec::new(), redo: Vec::new(), last_change: None }
+    }
+
+    /// Records `before`, the state as it was just before a change.
+    ///
+    /// A change with a `kind` is merged into the previous step if that was of the same kind and
+    /// happened moments ago. Changes without one always get their own step.
+    pub fn record(&mut self, before: &T, kind: Option<K>) {
+        let now = Instant::now();
+        let coalesce = self.last_change.as_ref().is_some_and(|(last, at)| {
+            kind.as_ref() == Some(last) && now.duration_since(*at) < COALESCE_WINDOW
+        });
+        self.last_change = kind.map(|kind| (kind, now));
+        if coalesce {
+            return;
+        }
+
+        self.undo.push(before.clone());
+        if self.undo.len() > MAX_STEPS {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+    }
+
+    /// Steps back from `current`, returning the state to restore.
+    pub fn undo(&mut self, current: &T) -> Option<T> {
+        let previous = self.undo.pop()?;
+        self.redo.push(current.clone());
+        self.last_change = None;
+        Some(previous)
+    }
+
+    /// Re-applies the last undone change on top of `current`, returning the state to restore.
+    pub fn redo(&mut self, current: &T) -> Option<T> {
+        let next = self.redo.pop()?;
+        self.undo.push(current.clone());
+        self.last_change = None;
+        Some(next)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Forgets all steps, e.g. after the state was replaced from outside.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.last_change = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records changes of `state` to each of `values` in turn, returning the final state.
+    fn change(history: &mut History<u8, &'static str>, mut state: u8, changes: &[(u8, Option<&'static str>)]) -> u8 {
+        for &(value, kind) in changes {
+            history.record(&state, kind);
+            state = value;
+        }
+        state
+    }
+
+    #[test]
+    fn merges_consecutive_changes_of_the_same_kind() {
+        let mut history = History::new();
+        let state = change(&mut history, 0, &[(1, Some("slider")), (2, Some("slider")), (3, Some("slider"))]);
+
+        assert_eq!(history.undo(&state), Some(0));
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn starts_a_new_step_for_another_kind() {
+        let mut history = History::new();
+        let state = change(&mut history, 0, &[(1, Some("slider")), (2, Some("toggle")), (3, Some("slider"))]);
+
+        assert_eq!(history.undo(&state), Some(2));
+        assert_eq!(history.undo(&2), Some(1));
+        assert_eq!(history.undo(&1), Some(0));
+        assert!(!history.can_undo());
+
+        // Changes without a kind are never merged
+        let mut history = History::new();
+        let state = change(&mut history, 0, &[(1, None), (2, None)]);
+        assert_eq!(history.undo(&state), Some(1));
+    }
+
+    #[test]
+    fn a_change_after_undo_drops_redo() {
+        let mut history = History::new();
+        let state = change(&mut history, 0, &[(1, Some("a")), (2, Some("b"))]);
+        let state = history.undo(&state).unwrap();
+        assert!(history.can_redo());
+
+        // The same kind as the undone step still starts a new one
+        let state = change(&mut history, state, &[(5, Some("b"))]);
+        assert!(!history.can_redo());
+        assert_eq!(history.redo(&state), None);
+        assert_eq!(history.undo(&state), Some(1));
+        assert_eq!(history.undo(&1), Some(0));
+    }
+}
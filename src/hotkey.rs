@@ -0,0 +1,177 @@
+use std::fmt;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use windows::Win32::Foundation::{LPARAM, WPARAM};
+use windows::Win32::System::Threading::GetCurrentThreadId;
+use windows::Win32::UI::Input::KeyboardAndMouse::{RegisterHotKey, UnregisterHotKey, HOT_KEY_MODIFIERS, MOD_NOREPEAT};
+use windows::Win32::UI::WindowsAndMessaging::{
+    GetMessageW, PeekMessageW, PostThreadMessageW, MSG, PM_NOREMOVE, WM_HOTKEY, WM_QUIT, WM_USER,
+};
+
+pub const MOD_ALT: u32 = 0x1;
+pub const MOD_CONTROL: u32 = 0x2;
+pub const MOD_SHIFT: u32 = 0x4;
+pub const MOD_WIN: u32 = 0x8;
+
+/// A system-wide keyboard shortcut such as `Ctrl+Alt+Shift+O`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hotkey {
+    /// `MOD_*` flags for `RegisterHotKey`.
+    pub modifiers: u32,
+    /// Virtual-key code.
+    pub key: u32,
+    name: String,
+}
+
+impl Hotkey {
+    /// Parses `Ctrl+Alt+O`-style shortcuts. Modifiers are `Ctrl`, `Alt`, `Shift` and `Win`; keys are
+    /// letters, digits, `F1`-`F24`, arrows, `PageUp`/`PageDown`, `Home`/`End`, `Plus`/`Minus` and a
+    /// few others. Case doesn't matter.
+    pub fn parse(text: &str) -> Result<Hotkey, String> {
+        let mut modifiers = 0;
+        let mut key = None;
+        for part in text.split('+').map(str::trim) {
+            if key.is_some() {
+                return Err(format!("`{}`: the key must come last, after the modifiers", text));
+            }
+            match (modifier(part), part.to_ascii_lowercase().as_str()) {
+                (Some(flag), _) => modifiers |= flag,
+                (None, "") => return Err(format!("`{}` has an empty key name", text)),
+                (None, name) => key = Some(virtual_key(name).ok_or_else(|| format!("`{}`: unknown key `{}`", text, part))?),
+            }
+        }
+        let key = key.ok_or_else(|| format!("`{}` has no key, only modifiers", text))?;
+        Ok(Hotkey { modifiers, key, name: text.to_string() })
+    }
+}
+
+impl fmt::Display for Hotkey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Parses a modifier combination without a key, such as `Ctrl+Alt`, into `MOD_*` flags.
+pub fn parse_modifiers(text: &str) -> Result<u32, String> {
+    let mut modifiers = 0;
+    for part in text.split('+').map(str::trim) {
+        modifiers |= modifier(part).ok_or_else(|| format!("`{}`: `{}` is not Ctrl, Alt, Shift or Win", text, part))?;
+    }
+    Ok(modifiers)
+}
+
+fn modifier(name: &str) -> Option<u32> {
+    match name.to_ascii_lowercase().as_str() {
+        "ctrl" | "control" => Some(MOD_CONTROL),
+        "alt" => Some(MOD_ALT),
+        "shift" => Some(MOD_SHIFT),
+        "win" | "super" => Some(MOD_WIN),
+        _ => None,
+    }
+}
+
+fn virtual_key(name: &str) -> Option<u32> {
+    let code = match name {
+        "space" => 0x20,
+        "pageup" => 0x21,
+        "pagedown" => 0x22,
+        "end" => 0x23,
+        "home" => 0x24,
+        "left" => 0x25,
+        "up" => 0x26,
+        "right" => 0x27,
+        "down" => 0x28,
+        "insert" => 0x2D,
+        "delete" => 0x2E,
+        "pause" => 0x13,
+        "esc" | "escape" => 0x1B,
+        "plus" | "=" => 0xBB,
+        "minus" | "-" => 0xBD,
+        "numplus" => 0x6B,
+        "numminus" => 0x6D,
+        _ => {
+            let mut chars = name.chars();
+            return match (chars.next(), chars.as_str()) {
+                (Some(c @ ('a'..='z' | '0'..='9')), "") => Some(c.to_ascii_uppercase() as u32),
+                (Some('f'), number) => number.parse::<u32>().ok()
+                    .filter(|n| (1..=24).contains(n))
+                    .map(|n| 0x70 + n - 1),
+                _ => None,
+            };
+        }
+    };
+    Some(code)
+}
+
+/// Keeps hotkeys registered while alive. Dropping it unregisters them, and only returns once
+/// they are, so the same hotkeys can be registered again right away.
+pub struct HotkeyListener {
+    thread_id: u32,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for HotkeyListener {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = PostThreadMessageW(self.thread_id, WM_QUIT, WPARAM(0), LPARAM(0));
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Gives the calling thread a message queue, so a `WM_QUIT` posted to it can't get lost.
+pub(crate) fn ensure_message_queue() {
+    let mut msg = MSG::default();
+    unsafe {
+        let _ = PeekMessageW(&mut msg, None, WM_USER, WM_USER, PM_NOREMOVE);
+    }
+}
+
+/// Registers `bindings` as system-wide hotkeys and calls `on_hotkey` with the bound value whenever
+/// one is pressed.
+///
+/// Hotkeys belong to the thread that registers them, so they get a thread with its own message
+/// loop. Returns the listener along with the hotkeys that couldn't be registered, usually because
+/// another app already owns them.
+pub fn listen<T, F>(bindings: Vec<(Hotkey, T)>, on_hotkey: F) -> (HotkeyListener, Vec<Hotkey>)
+where
+    T: Clone + Send + 'static,
+    F: Fn(T) + Send + 'static,
+{
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        ensure_message_queue();
+        let mut registered = Vec::new();
+        let mut failed = Vec::new();
+        for (id, (hotkey, value)) in bindings.into_iter().enumerate() {
+            let modifiers = HOT_KEY_MODIFIERS(hotkey.modifiers) | MOD_NOREPEAT;
+            match unsafe { RegisterHotKey(None, id as i32, modifiers, hotkey.key) } {
+                Ok(()) => registered.push((id as i32, value)),
+                Err(_) => failed.push(hotkey),
+            }
+        }
+        let _ = ready_tx.send((unsafe { GetCurrentThreadId() }, failed));
+
+        let mut msg = MSG::default();
+        // GetMessageW returns 0 for WM_QUIT and -1 on errors
+        while unsafe { GetMessageW(&mut msg, None, 0, 0) }.0 > 0 {
+            if msg.message != WM_HOTKEY {
+                continue;
+            }
+            if let Some((_, value)) = registered.iter().find(|(id, _)| *id as usize == msg.wParam.0) {
+                on_hotkey(value.clone());
+            }
+        }
+
+        for (id, _) in registered {
+            unsafe {
+                let _ = UnregisterHotKey(None, id);
+            }
+        }
+    });
+
+    let (thread_id, failed) = ready_rx.recv().expect("Hotkey thread exited before registering");
+    (HotkeyListener { thread_id, thread: Some(thread) }, failed)
+}
@@ -0,0 +1,45 @@
+use std::path::{Path, PathBuf};
+use tracing::level_filters::LevelFilter;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
+
+/// Daily log files older than this many days are deleted.
+const MAX_LOG_FILES: usize = 7;
+
+/// Level used without `--log-level` or `--verbose`.
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::INFO;
+
+/// Directory the log files go to: the one the config file is in.
+pub fn log_directory(config_path: &str) -> PathBuf {
+    match Path::new(config_path).parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Starts writing kester's log entries at `level` and above to `kester.<date>.log` in
+/// `directory`, one file per day. Other crates only log warnings and errors.
+///
+/// Lines are written as they happen rather than from a background thread, so nothing is lost
+/// when kester exits.
+pub fn init(directory: &Path, level: LevelFilter) -> Result<(), String> {
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix("kester")
+        .filename_suffix("log")
+        .max_log_files(MAX_LOG_FILES)
+        .build(directory)
+        .map_err(|e| format!("can't create log file in {}: {}", directory.display(), e))?;
+
+    let targets = Targets::new()
+        .with_default(LevelFilter::WARN)
+        .with_target("kester", level);
+
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_writer(appender).with_ansi(false))
+        .with(targets)
+        .try_init()
+        .map_err(|e| e.to_string())
+}
@@ -5,38 +5,152 @@ mod built_info {
 use iced::{widget::{Button, Column, Row, Text, Container, Scrollable}, Length, Alignment, Color, Application, Theme, Command, Element, window};
 use iced::theme;
 use iced::event::{self, Event};
+use iced::keyboard;
 use windows::{
     Win32::Foundation::*,
     Win32::UI::WindowsAndMessaging::*
 };
 use windows::Win32::System::ProcessStatus::*;
 use windows::Win32::System::Threading::*;
+use windows::Win32::Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY};
 use std::path::PathBuf;
+use std::time::Duration;
 use windows::Win32::Graphics::Gdi::{RedrawWindow, RDW_FRAME, RDW_INVALIDATE, RDW_UPDATENOW};
 use std::sync::{Mutex};
-use iced::widget::{Checkbox, Slider};
+use iced::widget::{Checkbox, PickList, Slider, TextInput};
 use iced::window::{Id, Mode};
 use once_cell::sync::Lazy;
-use tray_item::TrayItem;
+use tracing::{debug, error, info, warn};
+use tray_item::{TIError, TrayItem};
 use std::sync::Arc;
-use std::sync::mpsc::{self, Receiver};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use iced::futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use iced::futures::StreamExt;
 
+mod cli;
 mod config;
+mod error;
+mod history;
+mod hotkey;
+mod logging;
+mod wheel;
 
-use config::{Config, load_config};
+use cli::CliCommand;
+use config::{Config, ConfigFile, Diagnostic, load_config};
+use error::{ApplyFailure, KesterError};
+use history::History;
+use hotkey::{Hotkey, HotkeyListener};
+use wheel::WheelHook;
 
-static WINDOW_INFO_BUFFER: Lazy<Mutex<Vec<WindowInfo>>> = Lazy::new(|| Mutex::new(Vec::new()));
+/// How long a window gets to answer before it's treated as hung and left alone.
+const HUNG_WINDOW_TIMEOUT_MS: u32 = 500;
+
+/// With `mouse_wheel.persist`, the new opacity is saved once the wheel has been still this long
+/// rather than on every notch.
+const WHEEL_SAVE_DELAY: Duration = Duration::from_millis(700);
+
+/// Oldest errors are dropped from the status area beyond this many.
+const MAX_STATUS_ERRORS: usize = 5;
+
+/// Window classes of Store (UWP) app frames, which the system composes itself.
+const UWP_FRAME_CLASSES: [&str; 2] = ["ApplicationFrameWindow", "Windows.UI.Core.CoreWindow"];
+
+/// How each window looked before kester first changed it, keyed by window handle.
+static ORIGINAL_TRANSPARENCY: Lazy<Mutex<HashMap<isize, OriginalTransparency>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy)]
+struct OriginalTransparency {
+    layered: bool,
+    /// Color key, alpha and flags of a layered window, if they could be read.
+    attributes: Option<(COLORREF, u8, LAYERED_WINDOW_ATTRIBUTES_FLAGS)>,
+}
 
 fn main() -> iced::Result {
+    let cli = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            cli::attach_parent_console();
+            eprintln!("kester: {}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    match &cli.command {
+        CliCommand::Gui => {}
+        CliCommand::Schema => {
+            cli::attach_parent_console();
+            println!("{}", config::json_schema());
+            return Ok(());
+        }
+        CliCommand::Profile(name) => {
+            cli::attach_parent_console();
+            match cli::run_profile(&cli.config_file(), name.as_deref()) {
+                Ok(output) => println!("{}", output),
+                Err(e) => {
+                    eprintln!("kester: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        CliCommand::Explain(query) => {
+            cli::attach_parent_console();
+            match cli::run_explain(&cli.config_file(), query.as_deref()) {
+                Ok(output) => println!("{}", output),
+                Err(e) => {
+                    eprintln!("kester: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        CliCommand::Reset => {
+            cli::attach_parent_console();
+            println!("Made {} window(s) fully opaque", make_all_windows_opaque());
+            match cli::run_pause(&cli.config_file(), true) {
+                Ok(output) => println!("{}", output),
+                Err(e) => {
+                    eprintln!("kester: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        CliCommand::Resume => {
+            cli::attach_parent_console();
+            match cli::run_pause(&cli.config_file(), false) {
+                Ok(output) => println!("{}", output),
+                Err(e) => {
+                    eprintln!("kester: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return Ok(());
+        }
+        CliCommand::Help => {
+            cli::attach_parent_console();
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+    }
+
+    let config_file = cli.config_file();
+    let log_directory = logging::log_directory(&config_file.path);
+    if let Err(e) = logging::init(&log_directory, cli.log_level.unwrap_or(logging::DEFAULT_LEVEL)) {
+        eprintln!("kester: {}", e);
+    }
+    info!(version = built_info::PKG_VERSION, config = %config_file.path, "kester started");
+
     let settings = iced::Settings {
         window: window::Settings {
             size: iced::Size::new(700.0, 900.0),
             resizable: false,
             decorations: true,
-            exit_on_close_request: true,
+            // Closing goes through `Message::CloseRequested`, which restores the windows kester changed
+            exit_on_close_request: false,
             ..Default::default()
         },
-        ..Default::default()
+        ..iced::Settings::with_flags(config_file)
     };
 
     WindowManager::run(settings)
@@ -44,40 +158,187 @@ fn main() -> iced::Result {
 
 struct WindowManager {
     config: Config,
+    config_file: ConfigFile,
+    watched_paths: Arc<Mutex<Vec<String>>>,
     windows: Vec<WindowInfo>,
-    selected_window: Option<usize>,
+    selected_window: Option<WindowId>,
     current_transparency: u8,
     persist_setting: bool,
     default_opacity: Option<u8>,
     use_default_opacity: bool,
-    tray: Option<Arc<TrayItem>>,
+    tray: Option<TrayItem>,
+    config_errors: Vec<Diagnostic>,
+    /// Errors shown in the status area until dismissed, oldest first.
+    errors: Vec<KesterError>,
+    history: History<Config, ConfigChange>,
+    hotkeys: Option<HotkeyListener>,
+    wheel_hook: Option<WheelHook>,
+    /// Wheel movement too small to make up a whole notch yet, e.g. from a touchpad.
+    wheel_remainder: i32,
+    /// Window handle and opacity of wheel turns waiting for `WHEEL_SAVE_DELAY` to be saved.
+    wheel_pending: Option<(isize, u8)>,
+    /// Counts wheel turns, so only the save scheduled by the last one goes through.
+    wheel_turns: u64,
+    /// Hotkeys that are configured but couldn't be registered.
+    hotkey_errors: Vec<String>,
+    /// Why the configured mouse wheel hook couldn't be installed.
+    wheel_errors: Vec<String>,
+    filter: ListFilter,
+    /// Show the window list collapsed under each executable.
+    grouped: bool,
+    expanded_groups: HashSet<String>,
+    /// Last value of each executable's group slider.
+    group_opacity: HashMap<String, u8>,
+    /// Opacity windows had before the toggle hotkey made them opaque, keyed by window handle.
+    toggled: HashMap<isize, u8>,
+    tab: Tab,
+    /// Windows found so far while a refresh is running in the background.
+    refreshing: Option<usize>,
+    /// Another refresh was asked for while one was running.
+    refresh_again: bool,
+    /// Rules tab edits that haven't been applied yet.
+    rule_draft: Option<Vec<config::WindowConfig>>,
     window_visible: bool,
-    _tx: mpsc::Sender<Message>,
-    _rx: Arc<Mutex<Receiver<Message>>>,
+    _tx: UnboundedSender<Message>,
+    /// Messages from the tray, hotkeys and background threads. The lock is async, so waiting for
+    /// the next message never blocks an executor thread.
+    _rx: Arc<iced::futures::lock::Mutex<UnboundedReceiver<Message>>>,
+}
+
+/// Identifies a window across refreshes. Window handles are reused once a window closes, so the
+/// start time of the owning process tells a new window apart from an old one with the same handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct WindowId {
+    hwnd: isize,
+    /// Process creation time as a `FILETIME` tick count, or 0 if the process couldn't be opened.
+    process_started: u64,
 }
 
 #[derive(Debug, Clone)]
 struct WindowInfo {
+    id: WindowId,
     title: String,
     exe_name: String,
+    class_name: String,
     transparency: String,
     hwnd: HWND,
+    /// False if the window didn't answer in time. Hung windows are listed but never changed, as
+    /// changing their style would wait for them.
+    responding: bool,
+    /// Outcome of the last time kester changed the window, `None` until it tries.
+    last_apply: Option<Result<(), ApplyFailure>>,
+}
+
+impl WindowInfo {
+    fn is_layered(&self) -> bool {
+        self.transparency != "N/A"
+    }
+}
+
+/// Which windows the list shows. Applied to the last enumeration, without re-enumerating.
+#[derive(Debug, Clone, Default)]
+struct ListFilter {
+    /// Case-insensitive text to look for in the title, executable or class name.
+    text: String,
+    with_rule: bool,
+    modified: bool,
+    layered: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FilterToggle {
+    WithRule,
+    Modified,
+    Layered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tab {
+    Windows,
+    Rules,
+}
+
+/// An edit to the rule at some index of the active rule set, made in the Rules tab.
+#[derive(Debug, Clone)]
+enum RuleEdit {
+    Title(String),
+    Executable(String),
+    Opacity(u8),
+    Enabled(bool),
+    MoveUp,
+    MoveDown,
+    Duplicate,
+    Delete,
 }
 
 #[derive(Debug, Clone)]
 enum Message {
     RefreshWindows,
-    SelectWindow(usize),
+    /// A background refresh has looked at this many windows.
+    RefreshProgress(usize),
+    /// A background refresh finished, with the windows it found and what went wrong applying rules.
+    RefreshDone(Vec<WindowInfo>, Vec<KesterError>),
+    SelectWindow(WindowId),
+    /// The selected window's slider moved. Previewed right away, saved on `CommitTransparency`.
     UpdateTransparency(u8),
+    /// The selected window's slider was released.
+    CommitTransparency,
     TogglePersist(bool),
     UpdateDefaultOpacity(u8),
+    CommitDefaultOpacity,
     ToggleDefaultOpacity(bool),
     MinimizeToTray,
     ShowWindow,
+    ConfigChanged,
+    SelectProfile(ProfileChoice),
+    PanicReset,
+    TogglePaused(bool),
+    StepFocused(i16),
+    /// The wheel turned over a window while the modifier was held: window handle and wheel delta.
+    WheelOpacity(isize, i16),
+    /// The wheel has been still for `WHEEL_SAVE_DELAY` since the turn with this count.
+    SaveWheelOpacity(u64),
+    ToggleFocused,
+    PersistFocused,
+    Undo,
+    Redo,
+    FilterChanged(String),
+    ToggleFilter(FilterToggle, bool),
+    ToggleGrouped(bool),
+    ToggleGroupExpanded(String),
+    UpdateGroupOpacity(String, u8),
+    CommitGroupOpacity(String),
+    ToggleGroupPersist(String, bool),
+    SelectTab(Tab),
+    EditRule(usize, RuleEdit),
+    AddRule,
+    ApplyRules,
+    DiscardRules,
+    DismissErrors,
     CloseRequested,
     Ignore
 }
 
+/// What a config change touched. Repeated changes to the same target are undone as one step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ConfigChange {
+    /// Mouse wheel turns over a window, keyed by window handle.
+    Transparency(isize),
+}
+
+/// Entry of the profile picker. `None` stands for the top-level settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ProfileChoice(Option<String>);
+
+impl std::fmt::Display for ProfileChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "Default"),
+        }
+    }
+}
+
 unsafe impl Send for WindowInfo {}
 unsafe impl Sync for WindowInfo {}
 
@@ -85,43 +346,72 @@ impl Application for WindowManager {
     type Executor = iced::executor::Default;
     type Message = Message;
     type Theme = Theme;
-    type Flags = ();
-    fn new(_flags: ()) -> (Self, Command<Message>) {
-        let config = load_config("config.yaml").unwrap_or_default();
-        let default_opacity = config.default_opacity;
-
-        let (tx, rx) = mpsc::channel();
-        let rx = Arc::new(Mutex::new(rx));
+    type Flags = ConfigFile;
+    fn new(config_file: ConfigFile) -> (Self, Command<Message>) {
+        // An invalid file is reported instead of being silently replaced by defaults
+        let (config, config_errors) = match load_config(&config_file) {
+            Ok(config) => (config, Vec::new()),
+            Err(errors) => (Config::default(), errors.0),
+        };
+        let default_opacity = config.default_opacity();
 
-        let mut tray = TrayItem::new("Transparency Manager", "tray_icon")
-            .expect("Failed to create tray icon");
+        let (tx, rx) = mpsc::unbounded();
+        let rx = Arc::new(iced::futures::lock::Mutex::new(rx));
 
-        let tx_show = tx.clone();
-        tray.add_menu_item("Show Window", move || {
-            tx_show.send(Message::ShowWindow).expect("Failed to send show message");
-        }).expect("Failed to add Show menu item");
+        let mut errors = Vec::new();
+        let tray = match build_tray(&tx, &config, &config_errors, &errors) {
+            Ok(tray) => Some(tray),
+            Err(e) => {
+                errors.push(KesterError::Tray(e.to_string()));
+                None
+            }
+        };
 
-        let tx_exit = tx.clone();
-        tray.add_menu_item("Exit", move || {
-            tx_exit.send(Message::CloseRequested).expect("Failed to send exit message");
-        }).expect("Failed to add Exit menu item");
+        let watched_paths = Arc::new(Mutex::new(watched_paths(&config, &config_file)));
+        watch_files_with_errors(&watched_paths, &config_errors);
+        let tx_watch = tx.clone();
+        config::watch_config(watched_paths.clone(), move || {
+            let _ = tx_watch.unbounded_send(Message::ConfigChanged);
+        });
 
-        (
-            WindowManager {
+        let mut manager = WindowManager {
                 config,
+                config_file,
+                watched_paths,
                 windows: Vec::new(),
                 selected_window: None,
                 current_transparency: 0,
                 persist_setting: false,
                 default_opacity,
                 use_default_opacity: default_opacity.is_some(),
-                tray: Some(Arc::new(tray)),
+                tray,
+                config_errors,
+                errors,
+                history: History::new(),
+                hotkeys: None,
+                wheel_hook: None,
+                wheel_remainder: 0,
+                wheel_pending: None,
+                wheel_turns: 0,
+                hotkey_errors: Vec::new(),
+                wheel_errors: Vec::new(),
+                toggled: HashMap::new(),
+                filter: ListFilter::default(),
+                grouped: false,
+                expanded_groups: HashSet::new(),
+                group_opacity: HashMap::new(),
+                tab: Tab::Windows,
+                refreshing: None,
+                refresh_again: false,
+                rule_draft: None,
                 window_visible: true,
                 _tx: tx,
                 _rx: rx,
-            },
-            Command::perform(async {}, |_| Message::RefreshWindows),
-        )
+        };
+        manager.install_wheel_hook();
+        manager.register_hotkeys();
+
+        (manager, Command::perform(async {}, |_| Message::RefreshWindows))
     }
 
     fn subscription(&self) -> iced::Subscription<Message> {
@@ -130,6 +420,13 @@ impl Application for WindowManager {
             event::listen().map(|event| {
                 match event {
                     Event::Window(Id::MAIN, window::Event::CloseRequested) => Message::CloseRequested,
+                    Event::Keyboard(keyboard::Event::KeyPressed { key: keyboard::Key::Character(c), modifiers, .. })
+                        if modifiers.command() => match c.as_str() {
+                            "z" if modifiers.shift() => Message::Redo,
+                            "z" => Message::Undo,
+                            "y" => Message::Redo,
+                            _ => Message::Ignore,
+                        },
                     Event::Window(Id::MAIN, window::Event::Resized { width, height }) => {
                         if width == 0 && height == 0 {
                             Message::MinimizeToTray
@@ -143,9 +440,10 @@ impl Application for WindowManager {
             iced::subscription::unfold("tray_events", (), move |_| {
                 let rx = rx.clone();
                 async move {
-                    match rx.lock().unwrap().recv() {
-                        Ok(message) => (message, ()),
-                        Err(_) => (Message::Ignore, ()),
+                    match rx.lock().await.next().await {
+                        Some(message) => (message, ()),
+                        // Every sender is gone, nothing more will arrive
+                        None => iced::futures::future::pending().await,
                     }
                 }
             })
@@ -161,7 +459,17 @@ impl Application for WindowManager {
             Message::Ignore => {
                 /* Do nothing */
             }
-            Message::CloseRequested => std::process::exit(0),
+            Message::DismissErrors => {
+                self.errors.clear();
+            }
+            Message::CloseRequested => {
+                info!("kester exiting");
+                self.save_wheel_opacity();
+                if self.config.restore_original_opacity {
+                    restore_all_window_transparency();
+                }
+                std::process::exit(0)
+            }
             Message::MinimizeToTray => {
                 self.window_visible = false;
                 return Command::batch(vec![
@@ -177,116 +485,411 @@ impl Application for WindowManager {
                     window::gain_focus(window::Id::MAIN),
                 ])
             }
+            Message::ConfigChanged => {
+                match load_config(&self.config_file) {
+                    Ok(config) => {
+                        let had_error = !self.config_errors.is_empty();
+                        self.config_errors.clear();
+                        // Our own saves also trigger the watcher; only re-apply on real changes
+                        if config != self.config || had_error {
+                            info!(config = %self.config_file.path, "config changed on disk, re-applying");
+                            *self.watched_paths.lock().unwrap() = watched_paths(&config, &self.config_file);
+                            // Undoing past an outside edit would silently revert it
+                            self.history.clear();
+                            // Applying a draft of the old rules would do the same
+                            self.rule_draft = None;
+                            let hotkeys_changed = config.hotkeys != self.config.hotkeys;
+                            let wheel_changed = config.mouse_wheel != self.config.mouse_wheel;
+                            if config.paused && !self.config.paused {
+                                // `kester reset` made these opaque; exiting mustn't make them see-through again
+                                ORIGINAL_TRANSPARENCY.lock().unwrap()
+                                    .retain(|&handle, _| get_window_transparency(HWND(handle as _)).is_some_and(|alpha| alpha < 255));
+                            }
+                            self.config = config;
+                            // Leave unchanged ones alone, re-registering briefly frees them for other apps
+                            if wheel_changed {
+                                self.install_wheel_hook();
+                            }
+                            if hotkeys_changed {
+                                self.register_hotkeys();
+                            }
+                            self.sync_default_opacity();
+                            self.update_tray();
+                            return Command::perform(async {}, |_| Message::RefreshWindows);
+                        }
+                    }
+                    Err(errors) => {
+                        // Keep the last valid config and surface the errors
+                        for diagnostic in &errors.0 {
+                            warn!(file = %diagnostic.file, line = ?diagnostic.line, rule = ?diagnostic.rule, "config problem: {}", diagnostic.message);
+                        }
+                        self.config_errors = errors.0;
+                        watch_files_with_errors(&self.watched_paths, &self.config_errors);
+                        self.update_tray();
+                    }
+                }
+            }
+            Message::SelectProfile(ProfileChoice(name)) => {
+                if let Err(e) = self.config.set_active_profile(name.as_deref()) {
+                    self.report(KesterError::ConfigParse { path: self.config_file.path.clone(), message: e });
+                    return Command::none();
+                }
+                info!(profile = ?name, "switched profile");
+                self.history.clear();
+                // The draft was made from the previous profile's rules
+                self.rule_draft = None;
+                self.sync_default_opacity();
+                self.save_config();
+                self.update_tray();
+                // Re-apply the new profile's rules right away
+                return Command::perform(async {}, |_| Message::RefreshWindows);
+            }
+            Message::PanicReset => {
+                let count = make_all_windows_opaque();
+                info!(windows = count, "made all windows opaque and paused rules");
+                self.config.paused = true;
+                self.save_config();
+                self.update_tray();
+                return Command::perform(async {}, |_| Message::RefreshWindows);
+            }
+            Message::TogglePaused(value) => {
+                info!(paused = value, "rule application toggled");
+                self.config.paused = value;
+                self.save_config();
+                self.update_tray();
+                // Resuming re-applies the rules
+                return Command::perform(async {}, |_| Message::RefreshWindows);
+            }
+            Message::StepFocused(delta) => {
+                let Some(window) = focused_window() else {
+                    return Command::none();
+                };
+                let current = get_window_transparency(window).map_or(100, alpha_to_percent) as i16;
+                let opacity = (current + delta).clamp(self.config.min_opacity as i16, 100) as u8;
+                self.set_opacity(window, opacity);
+                self.window_changed(window, opacity);
+            }
+            Message::ToggleFocused => {
+                let Some(window) = focused_window() else {
+                    return Command::none();
+                };
+                let current = get_window_transparency(window).map_or(100, alpha_to_percent);
+                let opacity = if current < 100 {
+                    self.toggled.insert(window.0 as isize, current);
+                    100
+                } else {
+                    // Back to what the rule says, or to where the window was before the toggle
+                    let rule = get_window_title(window)
+                        .and_then(|title| determine_opacity(&title, &get_window_exe_label(window), &self.config));
+                    match rule.or_else(|| self.toggled.remove(&(window.0 as isize))) {
+                        Some(opacity) => opacity,
+                        None => return Command::none(),
+                    }
+                };
+                self.set_opacity(window, opacity);
+                self.window_changed(window, opacity);
+            }
+            Message::WheelOpacity(handle, delta) => {
+                let total = self.wheel_remainder + delta as i32;
+                let notches = total / wheel::WHEEL_DELTA;
+                self.wheel_remainder = total % wheel::WHEEL_DELTA;
+                if notches == 0 {
+                    return Command::none();
+                }
+
+                let window = HWND(handle as _);
+                if !is_window_responding(window) {
+                    return Command::none();
+                }
+                let step = notches * self.config.mouse_wheel.step as i32;
+                let current = get_window_transparency(window).map_or(100, alpha_to_percent) as i32;
+                let opacity = (current + step).clamp(self.config.min_opacity as i32, 100) as u8;
+                self.set_opacity(window, opacity);
+                self.window_changed(window, opacity);
+
+                if self.config.mouse_wheel.persist {
+                    // Turning the wheel over another window saves the last one right away
+                    if self.wheel_pending.is_some_and(|(pending, _)| pending != handle) {
+                        self.save_wheel_opacity();
+                    }
+                    self.wheel_pending = Some((handle, opacity));
+                    self.wheel_turns += 1;
+                    let turn = self.wheel_turns;
+                    return Command::perform(sleep(WHEEL_SAVE_DELAY), move |_| Message::SaveWheelOpacity(turn));
+                }
+            }
+            Message::SaveWheelOpacity(turn) => {
+                if turn == self.wheel_turns {
+                    self.save_wheel_opacity();
+                }
+            }
+            Message::PersistFocused => {
+                if let Some(window) = focused_window() {
+                    let opacity = get_window_transparency(window).map_or(100, alpha_to_percent);
+                    self.persist_window(window, opacity, None);
+                }
+            }
+            Message::FilterChanged(text) => {
+                self.filter.text = text;
+            }
+            Message::ToggleFilter(toggle, value) => {
+                match toggle {
+                    FilterToggle::WithRule => self.filter.with_rule = value,
+                    FilterToggle::Modified => self.filter.modified = value,
+                    FilterToggle::Layered => self.filter.layered = value,
+                }
+            }
+            Message::ToggleGrouped(value) => {
+                self.grouped = value;
+            }
+            Message::ToggleGroupExpanded(exe_name) => {
+                if !self.expanded_groups.remove(&exe_name) {
+                    self.expanded_groups.insert(exe_name);
+                }
+            }
+            Message::UpdateGroupOpacity(exe_name, value) => {
+                let group: Vec<HWND> = self.windows.iter()
+                    .filter(|window| window.exe_name == exe_name && window.responding)
+                    .map(|window| window.hwnd)
+                    .collect();
+                for hwnd in group {
+                    self.set_opacity(hwnd, value);
+                    self.window_changed(hwnd, value);
+                }
+                self.group_opacity.insert(exe_name, value);
+            }
+            Message::CommitGroupOpacity(exe_name) => {
+                // A persisted group keeps its executable rule in step with the slider
+                let value = self.group_value(&exe_name);
+                let changed = self.config.rules().iter().any(|rule| {
+                    rule.title.is_none() && rule.executable.as_ref() == Some(&exe_name) && rule.opacity != value
+                });
+                if changed {
+                    self.history.record(&self.config, None);
+                    for rule in self.config.rules_mut().iter_mut() {
+                        if rule.title.is_none() && rule.executable.as_ref() == Some(&exe_name) {
+                            rule.opacity = value;
+                        }
+                    }
+                    self.save_config();
+                }
+            }
+            Message::ToggleGroupPersist(exe_name, value) => {
+                self.history.record(&self.config, None);
+                if value {
+                    // One executable rule replaces the per-window rules of the app
+                    let opacity = self.group_value(&exe_name);
+                    self.config.rules_mut().retain(|w| w.executable.as_ref() != Some(&exe_name));
+                    self.config.rules_mut().push(config::WindowConfig {
+                        title: None,
+                        executable: Some(exe_name),
+                        opacity,
+                        enabled: true,
+                        source: None,
+                    });
+                } else {
+                    self.config.rules_mut().retain(|w| !(w.title.is_none() && w.executable.as_ref() == Some(&exe_name)));
+                    let group: Vec<usize> = (0..self.windows.len())
+                        .filter(|&index| self.windows[index].exe_name == exe_name)
+                        .collect();
+                    for index in group {
+                        self.reapply_window(index);
+                    }
+                }
+                self.save_config();
+            }
+            Message::SelectTab(tab) => {
+                self.tab = tab;
+            }
+            Message::EditRule(index, edit) => {
+                let rules = self.rule_draft.get_or_insert_with(|| self.config.rules().clone());
+                if index >= rules.len() {
+                    return Command::none();
+                }
+                match edit {
+                    RuleEdit::Title(text) => rules[index].title = Some(text).filter(|text| !text.is_empty()),
+                    RuleEdit::Executable(text) => rules[index].executable = Some(text).filter(|text| !text.is_empty()),
+                    RuleEdit::Opacity(value) => rules[index].opacity = value,
+                    RuleEdit::Enabled(value) => rules[index].enabled = value,
+                    RuleEdit::MoveUp if index > 0 => rules.swap(index, index - 1),
+                    RuleEdit::MoveDown if index + 1 < rules.len() => rules.swap(index, index + 1),
+                    RuleEdit::MoveUp | RuleEdit::MoveDown => {}
+                    RuleEdit::Duplicate => {
+                        // An enabled copy would never be used, as the original matches first
+                        let copy = config::WindowConfig { enabled: false, ..rules[index].clone() };
+                        rules.insert(index + 1, copy);
+                    }
+                    RuleEdit::Delete => {
+                        rules.remove(index);
+                    }
+                }
+            }
+            Message::AddRule => {
+                self.rule_draft.get_or_insert_with(|| self.config.rules().clone()).push(config::WindowConfig {
+                    title: None,
+                    executable: None,
+                    opacity: 100,
+                    enabled: true,
+                    source: None,
+                });
+            }
+            Message::ApplyRules => {
+                // Saving rules the file can't be loaded with would lock the config
+                if !self.draft_problems().is_empty() {
+                    return Command::none();
+                }
+                let Some(rules) = self.rule_draft.take() else {
+                    return Command::none();
+                };
+                if rules != *self.config.rules() {
+                    info!(rules = rules.len(), "applied rule editor changes");
+                    self.history.record(&self.config, None);
+                    *self.config.rules_mut() = rules;
+                    self.rules_changed();
+                }
+            }
+            Message::DiscardRules => {
+                self.rule_draft = None;
+            }
+            Message::Undo => {
+                if let Some(config) = self.history.undo(&self.config) {
+                    info!("undo");
+                    self.restore_config(config);
+                }
+            }
+            Message::Redo => {
+                if let Some(config) = self.history.redo(&self.config) {
+                    info!("redo");
+                    self.restore_config(config);
+                }
+            }
             Message::UpdateDefaultOpacity(value) => {
                 self.default_opacity = Some(value);
-                self.config.default_opacity = Some(value);
 
                 // Apply new default opacity to all windows without explicit settings
-                for window in &self.windows {
-                    let has_explicit_setting = self.config.specific_windows.iter().any(|w|
+                let windows = if self.config.paused { &[][..] } else { &self.windows[..] };
+                let targets: Vec<HWND> = windows.iter()
+                    .filter(|window| window.responding)
+                    .filter(|window| !self.config.effective_rules().any(|w| w.enabled && (
                         w.title.as_ref().map_or(false, |t| t == &window.title) ||
                             w.executable.as_ref().map_or(false, |e| e == &window.exe_name)
-                    );
-
-                    if !has_explicit_setting {
-                        set_window_transparency(window.hwnd, value)
-                            .unwrap_or_else(|_| println!("Failed to set transparency for: {}", window.title));
-                    }
+                    )))
+                    .map(|window| window.hwnd)
+                    .collect();
+                for hwnd in targets {
+                    self.set_opacity(hwnd, value);
                 }
 
-                config::save_config(&self.config, "config.yaml").expect("Config saved successfully");
                 // self.default_opacity = Some(value);
                 // self.config.default_opacity = Some(value);
                 // config::save_config(&self.config, "config.yaml").expect("Config saved successfully");
             }
+            Message::CommitDefaultOpacity => {
+                if self.default_opacity != self.config.default_opacity() {
+                    self.history.record(&self.config, None);
+                    self.config.set_default_opacity(self.default_opacity);
+                    self.save_config();
+                }
+            }
             Message::ToggleDefaultOpacity(value) => {
+                self.history.record(&self.config, None);
                 self.use_default_opacity = value;
                 if !value {
                     self.default_opacity = None;
-                    self.config.default_opacity = None;
+                    self.config.set_default_opacity(None);
+                    for index in 0..self.windows.len() {
+                        self.reapply_window(index);
+                    }
                 } else {
                     let default_value = 100;
                     self.default_opacity = Some(default_value);
-                    self.config.default_opacity = Some(default_value);
+                    self.config.set_default_opacity(Some(default_value));
                 }
-                config::save_config(&self.config, "config.yaml").expect("Config saved successfully");
+                self.save_config();
             }
             Message::RefreshWindows => {
-                // Clear existing windows list
-                self.windows.clear();
-
-                // Clear the global buffer
-                WINDOW_INFO_BUFFER.lock().unwrap().clear();
-
-                // Enumerate windows and collect info
-                unsafe {
-                    EnumWindows(Some(enum_window), LPARAM(&self.config as *const _ as isize)).expect("TODO: panic message");
+                // One at a time so two refreshes never apply rules at once. The latest config is picked up afterwards.
+                if self.refreshing.is_some() {
+                    self.refresh_again = true;
+                    return Command::none();
                 }
+                self.refreshing = Some(0);
 
-                // Move windows from buffer to our state
-                self.windows = WINDOW_INFO_BUFFER.lock().unwrap().drain(..).collect();
+                // Forget windows that have been closed, their handles may get reused
+                ORIGINAL_TRANSPARENCY.lock().unwrap().retain(|&handle, _| unsafe { IsWindow(HWND(handle as _)) }.as_bool());
 
-                // Reset selection
-                self.selected_window = None;
+                return Command::perform(
+                    refresh_windows(self.config.clone(), self._tx.clone()),
+                    |(windows, errors)| Message::RefreshDone(windows, errors),
+                );
+            }
+            Message::RefreshProgress(count) => {
+                if self.refreshing.is_some() {
+                    self.refreshing = Some(count);
+                }
             }
-            Message::SelectWindow(index) => {
-                self.selected_window = Some(index);
+            Message::RefreshDone(found, errors) => {
+                debug!(windows = found.len(), failed = errors.len(), "refreshed window list");
+                self.refreshing = None;
+                self.merge_windows(found);
+                for error in errors {
+                    self.report(error);
+                }
+                if std::mem::take(&mut self.refresh_again) {
+                    return Command::perform(async {}, |_| Message::RefreshWindows);
+                }
+            }
+            Message::SelectWindow(id) => {
+                let Some(index) = self.windows.iter().position(|window| window.id == id) else {
+                    return Command::none();
+                };
+                self.selected_window = Some(id);
                 let window = &self.windows[index];
                 self.current_transparency = window.transparency
                     .trim_end_matches('%')
                     .parse()
                     .unwrap_or(100);
-                self.persist_setting = self.config.specific_windows.iter().any(|w|
+                self.persist_setting = self.config.rules().iter().any(|w|
                     w.title.as_ref().map_or(false, |t| t == &window.title) ||
                         w.executable.as_ref().map_or(false, |e| e == &window.exe_name)
                 );
             }
             Message::UpdateTransparency(value) => {
                 self.current_transparency = value;
-                if let Some(index) = self.selected_window {
+                if let Some(index) = self.selected().filter(|&index| self.windows[index].responding) {
+                    self.set_opacity(self.windows[index].hwnd, value);
+                }
+            }
+            Message::CommitTransparency => {
+                // Save to config if persist is checked, once per drag
+                if let Some(index) = self.selected().filter(|_| self.persist_setting) {
                     let window = &self.windows[index];
-                    set_window_transparency(window.hwnd, value).unwrap_or_else(|_| println!("Failed to set transparency"));
-
-                    // Save to config if persist is checked
-                    if self.persist_setting {
-                        // Remove any existing config for this window
-                        self.config.specific_windows.retain(|w| {
-                            w.title.as_ref().map_or(true, |t| t != &window.title) &&
-                                w.executable.as_ref().map_or(true, |e| e != &window.exe_name)
-                        });
-
-                        // Add new config
-                        self.config.specific_windows.push(config::WindowConfig {
-                            title: Some(window.title.clone()),
-                            executable: Some(window.exe_name.clone()),
-                            opacity: value,
-                        });
-
-                        // Save config to file
-                        config::save_config(&self.config, "config.yaml").expect("Config saved successfully");
+                    let (title, exe_name) = (window.title.clone(), window.exe_name.clone());
+                    let current = self.config.rules().iter().find(|w| w.title.as_ref() == Some(&title) && w.executable.as_ref() == Some(&exe_name));
+                    if current.map(|rule| rule.opacity) != Some(self.current_transparency) {
+                        info!(title = %title, exe = %exe_name, hwnd = window.hwnd.0 as isize, opacity = self.current_transparency, "saved window rule");
+                        self.history.record(&self.config, None);
+                        self.set_window_rule(title, exe_name, self.current_transparency);
+                        self.save_config();
                     }
                 }
             }
             Message::TogglePersist(value) => {
                 self.persist_setting = value;
-                if let Some(index) = self.selected_window {
+                if let Some(index) = self.selected() {
                     let window = &self.windows[index];
+                    self.history.record(&self.config, None);
                     if !value {
-                        // Remove config for this window
-                        self.config.specific_windows.retain(|w| {
-                            w.title.as_ref().map_or(true, |t| t != &window.title) &&
-                                w.executable.as_ref().map_or(true, |e| e != &window.exe_name)
-                        });
+                        // Remove the rule saved for this window, leaving broader rules that also match it
+                        self.config.rules_mut().retain(|w| !w.is_for_window(&window.title, &window.exe_name));
+                        self.reapply_window(index);
                     } else {
                         // Add new config entry with current transparency
-                        self.config.specific_windows.push(config::WindowConfig {
-                            title: Some(window.title.clone()),
-                            executable: Some(window.exe_name.clone()),
-                            opacity: self.current_transparency,
-                        });
+                        let (title, exe_name) = (window.title.clone(), window.exe_name.clone());
+                        self.set_window_rule(title, exe_name, self.current_transparency);
                     }
                     // Save config to file
-                    config::save_config(&self.config, "config.yaml").expect("Config saved successfully");
+                    self.save_config();
                 }
             }
         }
@@ -298,46 +901,118 @@ impl Application for WindowManager {
             .size(40)
             .style(Color::from([0.5, 0.5, 0.5]));
 
-        let refresh_button = Button::new(Text::new("Refresh"))
-            .on_press(Message::RefreshWindows)
+        let refresh_button = match self.refreshing {
+            Some(count) => Button::new(Text::new(format!("Scanning... {}", count))),
+            None => Button::new(Text::new("Refresh")).on_press(Message::RefreshWindows),
+        }.padding(10);
+
+        let undo_button = Button::new(Text::new("Undo"))
+            .on_press_maybe(self.history.can_undo().then_some(Message::Undo))
+            .padding(10);
+
+        let redo_button = Button::new(Text::new("Redo"))
+            .on_press_maybe(self.history.can_redo().then_some(Message::Redo))
+            .padding(10);
+
+        let panic_button = Button::new(Text::new("Make all opaque"))
+            .on_press(Message::PanicReset)
+            .style(theme::Button::Destructive)
             .padding(10);
 
         let header = Row::new()
             .push(title)
             .push(refresh_button)
+            .push(undo_button)
+            .push(redo_button)
+            .push(panic_button)
             .align_items(Alignment::Center)
             .spacing(20);
 
+        let profile_section = if self.config.profiles.is_empty() {
+            Row::new()
+        } else {
+            let choices: Vec<ProfileChoice> = std::iter::once(ProfileChoice(None))
+                .chain(self.config.profiles.keys().map(|name| ProfileChoice(Some(name.clone()))))
+                .collect();
+            Row::new()
+                .push(Text::new("Profile:").size(14))
+                .push(PickList::new(
+                    choices,
+                    Some(ProfileChoice(self.config.active_profile.clone())),
+                    Message::SelectProfile,
+                ))
+                .align_items(Alignment::Center)
+                .spacing(20)
+        };
+
         let default_opacity_section = if self.use_default_opacity {
             Row::new()
                 .push(Text::new("Default Opacity:").size(14))
                 .push(Slider::new(
-                    0..=100,
+                    self.config.min_opacity..=100,
                     self.default_opacity.unwrap_or(100),
                     Message::UpdateDefaultOpacity,
-                ))
+                ).on_release(Message::CommitDefaultOpacity))
                 .push(Text::new(format!("{}%", self.default_opacity.unwrap_or(100))).size(14))
                 .spacing(20)
         } else {
             Row::new()
         };
 
-        let selected_info = if let Some(index) = self.selected_window {
+        let selected_info = if let Some(index) = self.selected() {
             let window = &self.windows[index];
-            format!("{} ({})", window.title, window.exe_name)
+            match self.config.find_rule(&window.title, &window.exe_name) {
+                Some(rule) => format!(
+                    "{} ({}) - rule from {}",
+                    window.title,
+                    window.exe_name,
+                    rule.source.as_deref().unwrap_or(&self.config_file.path)
+                ),
+                None => format!("{} ({})", window.title, window.exe_name),
+            }
         } else {
             "No window selected".to_string()
         };
 
         let selected_info_text = Text::new(selected_info).size(16);
 
-        let transparency_section = if let Some(_) = self.selected_window {
+        let status = if self.errors.is_empty() {
+            Column::new()
+        } else {
+            self.errors.iter().fold(Column::new().spacing(4), |column, error| {
+                column.push(Text::new(error.to_string()).size(12).style(Color::from([0.8, 0.2, 0.2])))
+            }).push(Button::new(Text::new("Dismiss").size(12))
+                .on_press(Message::DismissErrors)
+                .style(theme::Button::Secondary))
+        };
+
+        let input_errors = self.wheel_errors.iter().chain(&self.hotkey_errors).fold(Column::new().spacing(4), |column, error| {
+            column.push(Text::new(error).size(12).style(Color::from([0.8, 0.2, 0.2])))
+        });
+
+        let config_errors = if self.config_errors.is_empty() {
+            Column::new()
+        } else {
+            self.config_errors.iter().fold(
+                Column::new()
+                    .push(Text::new(format!("{} has problems - changes are not saved until they are fixed:", self.config_file.path))
+                        .size(14)
+                        .style(Color::from([0.8, 0.2, 0.2]))),
+                |column, diagnostic| column.push(
+                    Text::new(diagnostic.to_string())
+                        .size(12)
+                        .style(Color::from([0.8, 0.2, 0.2]))
+                )
+            ).spacing(4)
+        };
+
+        let transparency_section = if let Some(_) = self.selected() {
             Row::new()
                 .push(Slider::new(
-                    0..=100,
+                    self.config.min_opacity..=100,
                     self.current_transparency,
                     Message::UpdateTransparency,
-                ))
+                ).on_release(Message::CommitTransparency))
                 .push(Text::new(format!("{}%", self.current_transparency)).size(14))
                 .push(Checkbox::new(
                     "Persist",
@@ -348,45 +1023,121 @@ impl Application for WindowManager {
             Row::new()
         };
 
-        let windows_list = self.windows.iter().enumerate().fold(
-            Column::new().spacing(10),
-            |column, (index, window)| {
-                column.push(
-                    Button::new(
-                        Container::new(
-                            Column::new()
-                                .push(Text::new(&window.title).size(18))
-                                .push(Text::new(format!("Executable: {}", window.exe_name)).size(12))
-                                .push(Text::new(format!("Transparency: {}", window.transparency)).size(12))
-                        )
-                            .style(theme::Container::Box)
-                            .padding(10)
-                    )
-                        .on_press(Message::SelectWindow(index))
-                        .style(if Some(index) == self.selected_window {
-                            theme::Button::Primary
-                        } else {
-                            theme::Button::Secondary
-                        })
-                )
+        let filter_section = Column::new()
+            .push(TextInput::new("Filter by title, executable or class", &self.filter.text)
+                .on_input(Message::FilterChanged)
+                .padding(8))
+            .push(Row::new()
+                .push(Checkbox::new("Only with a rule", self.filter.with_rule)
+                    .on_toggle(|value| Message::ToggleFilter(FilterToggle::WithRule, value)))
+                .push(Checkbox::new("Only modified", self.filter.modified)
+                    .on_toggle(|value| Message::ToggleFilter(FilterToggle::Modified, value)))
+                .push(Checkbox::new("Only layered", self.filter.layered)
+                    .on_toggle(|value| Message::ToggleFilter(FilterToggle::Layered, value)))
+                .spacing(20))
+            .spacing(10);
+
+        let visible_windows: Vec<&WindowInfo> = self.windows.iter()
+            .filter(|window| self.filter_matches(window))
+            .collect();
+
+        let window_count = Text::new(format!("Showing {} of {} windows", visible_windows.len(), self.windows.len())).size(12);
+
+        let windows_list = if self.grouped {
+            let mut groups: BTreeMap<&str, Vec<&WindowInfo>> = BTreeMap::new();
+            for window in visible_windows {
+                groups.entry(window.exe_name.as_str()).or_default().push(window);
             }
-        );
+            groups.into_iter().fold(Column::new().spacing(10), |column, (exe_name, windows)| {
+                let expanded = self.expanded_groups.contains(exe_name);
+                let value = self.group_value(exe_name);
+                let exe_for_slider = exe_name.to_string();
+                let exe_for_persist = exe_name.to_string();
+                let exe_for_release = exe_name.to_string();
+                let header = Row::new()
+                    .push(Button::new(Text::new(format!(
+                        "{} {} ({} windows)",
+                        if expanded { "▼" } else { "▶" },
+                        exe_name,
+                        windows.len()
+                    )))
+                        .on_press(Message::ToggleGroupExpanded(exe_name.to_string()))
+                        .style(theme::Button::Secondary)
+                        .width(Length::FillPortion(2)))
+                    .push(Slider::new(
+                        self.config.min_opacity..=100,
+                        value,
+                        move |value| Message::UpdateGroupOpacity(exe_for_slider.clone(), value),
+                    ).on_release(Message::CommitGroupOpacity(exe_for_release)).width(Length::FillPortion(2)))
+                    .push(Text::new(format!("{}%", value)).size(14))
+                    .push(Checkbox::new("Persist", self.has_group_rule(exe_name))
+                        .on_toggle(move |value| Message::ToggleGroupPersist(exe_for_persist.clone(), value)))
+                    .align_items(Alignment::Center)
+                    .spacing(20);
+
+                let column = column.push(header);
+                if expanded {
+                    windows.into_iter().fold(column, |column, window| {
+                        column.push(Container::new(self.window_button(window)).padding([0, 0, 0, 30]))
+                    })
+                } else {
+                    column
+                }
+            })
+        } else {
+            visible_windows.into_iter().fold(
+                Column::new().spacing(10),
+                |column, window| column.push(self.window_button(window))
+            )
+        };
 
         let content = Scrollable::new(windows_list)
             .height(Length::Fill)
             .width(Length::Fill);
 
+        let tab_button = |label, tab| Button::new(Text::new(label))
+            .on_press(Message::SelectTab(tab))
+            .style(if self.tab == tab { theme::Button::Primary } else { theme::Button::Secondary })
+            .padding(10);
+        let tabs = Row::new()
+            .push(tab_button("Windows", Tab::Windows))
+            .push(tab_button(if self.has_unapplied_rules() { "Rules *" } else { "Rules" }, Tab::Rules))
+            .spacing(10);
+
+        let tab_content: Element<Message> = match self.tab {
+            Tab::Windows => Column::new()
+                .push(selected_info_text)
+                .push(transparency_section)
+                .push(filter_section)
+                .push(Row::new()
+                    .push(window_count)
+                    .push(Checkbox::new("Group by application", self.grouped).on_toggle(Message::ToggleGrouped))
+                    .align_items(Alignment::Center)
+                    .spacing(20))
+                .push(content)
+                .spacing(20)
+                .into(),
+            Tab::Rules => self.rules_tab(),
+        };
+
         Container::new(
             Column::new()
                 .push(header)
+                .push(config_errors)
+                .push(input_errors)
+                .push(status)
+                .push(profile_section)
+                .push(Checkbox::new(
+                    "Pause rules",
+                    self.config.paused,
+                ).on_toggle(Message::TogglePaused))
                 .push(Checkbox::new(
                     "Use Default Opacity",
                     self.use_default_opacity,
                 ).on_toggle(Message::ToggleDefaultOpacity))
                 .push(default_opacity_section)
-                .push(selected_info_text)
-                .push(transparency_section)
-                .push(content)
+                .push(tabs)
+                .push(tab_content)
                 .spacing(20)
         )
         .width(Length::Fill)
@@ -396,41 +1147,755 @@ impl Application for WindowManager {
     }
 }
 
-extern "system" fn enum_window(window: HWND, lparam: LPARAM) -> BOOL {
-    let config = unsafe { &*(lparam.0 as *const Config) };
-    unsafe {
-        if !is_window_visible_and_normal(window) {
-            return true.into();
-        }
-
-        let mut text: [u16; 512] = [0; 512];
-        let len = GetWindowTextW(window, &mut text);
-        if len > 0 {
-            let title = String::from_utf16_lossy(&text[..len as usize]);
-            if !title.is_empty() {
-                let exe_name = get_window_exe_name(window)
-                    .unwrap_or_else(|| "Unknown".to_string())
-                    .chars()
-                    .filter(|c| c.is_ascii_graphic())
-                    .collect::<String>();
-                if let Some(opacity) = determine_opacity(&title, &exe_name, config) {
-                    set_window_transparency(window, opacity).unwrap_or_else(|_| println!("Failed to set transparency for: {}", title));
-                }
-                let transparency = get_window_transparency(window).map_or("N/A".to_string(), |a| format!("{}%", (a as f32 / 255.0 * 100.0) as u8));
-
-                let window_info = WindowInfo {
-                    title,
-                    exe_name,
-                    transparency,
-                    hwnd: window
-                };
+impl WindowManager {
+    /// Writes the config back to disk, unless the file on disk has problems that the user is still fixing.
+    fn save_config(&mut self) {
+        if !self.config_errors.is_empty() {
+            return;
+        }
+        match config::save_config(&mut self.config, &self.config_file) {
+            Ok(()) => debug!(config = %self.config_file.path, "saved config"),
+            Err(e) => {
+                error!(config = %self.config_file.path, "{}", e);
+                self.report(e);
+            }
+        }
+    }
+
+    /// Mirrors the active default opacity into the GUI state.
+    fn sync_default_opacity(&mut self) {
+        self.default_opacity = self.config.default_opacity();
+        self.use_default_opacity = self.default_opacity.is_some();
+    }
+
+    /// Switches to a config from the undo history, saves it and re-applies it to the listed windows.
+    /// Pausing isn't undone this way, so an undo after a panic reset can't bring the old opacities back.
+    fn restore_config(&mut self, mut config: Config) {
+        config.paused = self.config.paused;
+        // The snapshot remembers the file as it was back then, not as it is now
+        config.file_contents = self.config.file_contents.take();
+        self.config = config;
+        // The draft was made from the rules being replaced
+        self.rule_draft = None;
+        self.sync_default_opacity();
+        self.rules_changed();
+    }
+
+    /// Saves edited rules and re-applies them to the listed windows.
+    fn rules_changed(&mut self) {
+        self.save_config();
+        // Group sliders show the new rules again
+        self.group_opacity.clear();
+
+        for index in 0..self.windows.len() {
+            self.reapply_window(index);
+        }
+
+        if let Some(index) = self.selected() {
+            let window = &self.windows[index];
+            if let Some(rule) = self.config.rules().iter().find(|w|
+                w.title.as_ref().is_some_and(|t| t == &window.title) ||
+                    w.executable.as_ref().is_some_and(|e| e == &window.exe_name)
+            ) {
+                self.current_transparency = rule.opacity;
+                self.persist_setting = true;
+            } else {
+                self.persist_setting = false;
+            }
+        }
+    }
+
+    /// Applies whatever opacity the config now gives the window at `index`. A window that no rule or
+    /// default applies to any more gets its original opacity back, unless the user opted out.
+    fn reapply_window(&mut self, index: usize) {
+        if self.config.paused {
+            return;
+        }
+        let window = &mut self.windows[index];
+        // Checked again, as the window may have hung since the list was refreshed
+        window.responding = window.responding && is_window_responding(window.hwnd);
+        if !window.responding {
+            return;
+        }
+        let rule = self.config.find_rule_index(&window.title, &window.exe_name).map(|(index, _)| index);
+        let opacity = determine_opacity(&window.title, &window.exe_name, &self.config);
+        match opacity {
+            Some(opacity) => debug!(title = %window.title, exe = %window.exe_name, hwnd = window.hwnd.0 as isize, rule = ?rule, opacity, "re-applying opacity"),
+            None if self.config.restore_original_opacity => debug!(title = %window.title, exe = %window.exe_name, hwnd = window.hwnd.0 as isize, "restoring original opacity"),
+            None => return,
+        }
+        if let Err(failure) = apply_to_window(window, opacity) {
+            warn!(title = %window.title, exe = %window.exe_name, hwnd = window.hwnd.0 as isize, rule = ?rule, code = failure.code, "could not change window: {}", failure.reason);
+            let error = failure.to_error(&window.title);
+            self.report(error);
+        }
+    }
+
+    /// Sets a window's opacity, reporting a failure in the status area and, for a listed window,
+    /// next to it in the list.
+    fn set_opacity(&mut self, window: HWND, opacity: u8) {
+        // Changing the style of a window that hung since the list was refreshed would freeze the GUI
+        if !is_window_responding(window) {
+            if let Some(info) = self.windows.iter_mut().find(|info| info.hwnd == window) {
+                info.responding = false;
+            }
+            debug!(hwnd = window.0 as isize, opacity, "skipped window that stopped responding");
+            return;
+        }
+        let (title, exe_name, result) = match self.windows.iter_mut().find(|info| info.hwnd == window) {
+            Some(info) => (info.title.clone(), info.exe_name.clone(), apply_to_window(info, Some(opacity))),
+            None => (
+                get_window_title(window).unwrap_or_default(),
+                get_window_exe_label(window),
+                set_window_transparency(window, opacity).map_err(|e| ApplyFailure::new(&e, None)),
+            ),
+        };
+        match result {
+            Ok(()) => debug!(title = %title, exe = %exe_name, hwnd = window.0 as isize, opacity, "set opacity"),
+            Err(failure) => {
+                warn!(title = %title, exe = %exe_name, hwnd = window.0 as isize, opacity, code = failure.code, "could not change window: {}", failure.reason);
+                self.report(failure.to_error(&title));
+            }
+        }
+    }
+
+    /// Shows an error in the status area. An error that is already listed isn't added again, so a
+    /// window that fails on every refresh doesn't flood it.
+    fn report(&mut self, error: KesterError) {
+        if self.errors.contains(&error) {
+            return;
+        }
+        let is_tray_error = matches!(error, KesterError::Tray(_));
+        self.errors.push(error);
+        if self.errors.len() > MAX_STATUS_ERRORS {
+            self.errors.remove(0);
+        }
+        // The tray menu is all there is to see while the window is hidden
+        if !self.window_visible && !is_tray_error {
+            self.update_tray();
+        }
+    }
+
+    /// Index of the selected window in the list, if it is still there.
+    fn selected(&self) -> Option<usize> {
+        let id = self.selected_window?;
+        self.windows.iter().position(|window| window.id == id)
+    }
+
+    /// Updates the window list with a fresh enumeration. Windows that are still open keep their
+    /// place and take on the new title and opacity, closed ones are dropped and new ones are added
+    /// at the end. The selection stays on the same window as long as it exists.
+    fn merge_windows(&mut self, mut found: Vec<WindowInfo>) {
+        let mut windows: Vec<WindowInfo> = self.windows.drain(..)
+            .filter_map(|known| {
+                let index = found.iter().position(|window| window.id == known.id)?;
+                let mut window = found.remove(index);
+                // A window no rule applies to wasn't touched by this refresh, its last outcome stands
+                if window.last_apply.is_none() {
+                    window.last_apply = known.last_apply;
+                }
+                Some(window)
+            })
+            .collect();
+        windows.extend(found);
+        self.windows = windows;
+
+        match self.selected() {
+            Some(index) => {
+                self.current_transparency = self.windows[index].transparency
+                    .trim_end_matches('%')
+                    .parse()
+                    .unwrap_or(100);
+            }
+            None => self.selected_window = None,
+        }
+    }
 
-                WINDOW_INFO_BUFFER.lock().unwrap().push(window_info);
+    /// Reflects an opacity change made outside the sliders in the window list.
+    fn window_changed(&mut self, hwnd: HWND, opacity: u8) {
+        if let Some(index) = self.windows.iter().position(|window| window.hwnd == hwnd) {
+            self.windows[index].transparency = format!("{}%", opacity);
+            if self.selected_window == Some(self.windows[index].id) {
+                self.current_transparency = opacity;
             }
         }
     }
+
+    fn window_button<'a>(&self, window: &'a WindowInfo) -> Element<'a, Message> {
+        Button::new(
+            Container::new(
+                Column::new()
+                    .push(Text::new(&window.title).size(18))
+                    .push(Text::new(format!("Executable: {}    Class: {}", window.exe_name, window.class_name)).size(12))
+                    .push(Text::new(if window.responding {
+                        format!("Transparency: {}", window.transparency)
+                    } else {
+                        format!("Transparency: {} - not responding, left alone", window.transparency)
+                    }).size(12))
+                    .push_maybe(match &window.last_apply {
+                        Some(Err(failure)) => Some(failure_text(failure)),
+                        _ => None,
+                    })
+            )
+                .style(theme::Container::Box)
+                .padding(10)
+        )
+            .on_press(Message::SelectWindow(window.id))
+            .style(if Some(window.id) == self.selected_window {
+                theme::Button::Primary
+            } else {
+                theme::Button::Secondary
+            })
+            .into()
+    }
+
+    /// Every rule of the active rule set, editable, followed by the read-only rules from included
+    /// and system-wide files.
+    fn rules_tab(&self) -> Element<'_, Message> {
+        let draft = self.rule_draft.as_ref().unwrap_or(self.config.rules());
+        let problems = self.draft_problems();
+        let rules = draft.iter().enumerate().fold(Column::new().spacing(10), |column, (index, rule)| {
+            let matchers = Row::new()
+                .push(Checkbox::new("", rule.enabled)
+                    .on_toggle(move |value| Message::EditRule(index, RuleEdit::Enabled(value))))
+                .push(TextInput::new("Title contains", rule.title.as_deref().unwrap_or(""))
+                    .on_input(move |text| Message::EditRule(index, RuleEdit::Title(text)))
+                    .padding(5))
+                .push(TextInput::new("Executable contains", rule.executable.as_deref().unwrap_or(""))
+                    .on_input(move |text| Message::EditRule(index, RuleEdit::Executable(text)))
+                    .padding(5))
+                .align_items(Alignment::Center)
+                .spacing(10);
+
+            let last = index + 1 == draft.len();
+            let controls = Row::new()
+                .push(Slider::new(
+                    self.config.min_opacity..=100,
+                    rule.opacity.max(self.config.min_opacity),
+                    move |value| Message::EditRule(index, RuleEdit::Opacity(value)),
+                ))
+                .push(Text::new(format!("{}%", rule.opacity)).size(14))
+                .push(Button::new(Text::new("↑"))
+                    .on_press_maybe((index > 0).then_some(Message::EditRule(index, RuleEdit::MoveUp))))
+                .push(Button::new(Text::new("↓"))
+                    .on_press_maybe((!last).then_some(Message::EditRule(index, RuleEdit::MoveDown))))
+                .push(Button::new(Text::new("Duplicate"))
+                    .on_press(Message::EditRule(index, RuleEdit::Duplicate))
+                    .style(theme::Button::Secondary))
+                .push(Button::new(Text::new("Delete"))
+                    .on_press(Message::EditRule(index, RuleEdit::Delete))
+                    .style(theme::Button::Destructive))
+                .align_items(Alignment::Center)
+                .spacing(10);
+
+            let entry = problems.iter()
+                .filter(|problem| problem.rule == Some(index))
+                .fold(Column::new().push(matchers).push(controls).spacing(10), |entry, problem| {
+                    entry.push(Text::new(problem.message.clone()).size(12).style(Color::from([0.8, 0.2, 0.2])))
+                });
+            column.push(Container::new(entry).style(theme::Container::Box).padding(10))
+        });
+
+        let included = self.config.layers.iter().rev()
+            .flat_map(|layer| layer.rules.iter().map(move |rule| (layer, rule)))
+            .fold(Column::new().spacing(6), |column, (layer, rule)| {
+                let matchers: Vec<String> = [("title", &rule.title), ("executable", &rule.executable)].iter()
+                    .filter_map(|(key, value)| value.as_ref().map(|value| format!("{} \"{}\"", key, value)))
+                    .collect();
+                let disabled = if rule.enabled { "" } else { " (disabled)" };
+                column.push(Text::new(format!("{} -> {}%{}  [{}]", matchers.join(" or "), rule.opacity, disabled, layer.path)).size(12))
+            });
+
+        let pending = if self.has_unapplied_rules() {
+            Row::new()
+                .push(Text::new("Unsaved changes").size(14).style(Color::from([0.8, 0.5, 0.0])))
+                .push(Button::new(Text::new("Apply")).on_press_maybe(problems.is_empty().then_some(Message::ApplyRules)).padding(10))
+                .push(Button::new(Text::new("Discard"))
+                    .on_press(Message::DiscardRules)
+                    .style(theme::Button::Secondary)
+                    .padding(10))
+                .align_items(Alignment::Center)
+                .spacing(20)
+        } else {
+            Row::new()
+        };
+
+        let mut column = Column::new()
+            .push(Text::new("Rules are tried from the top, the first enabled one that matches a window wins.").size(12))
+            .push(pending)
+            .push(rules)
+            .push(Button::new(Text::new("Add rule")).on_press(Message::AddRule).padding(10))
+            .spacing(20);
+        if self.config.layers.iter().any(|layer| !layer.rules.is_empty()) {
+            column = column
+                .push(Text::new("From included files (read-only, tried after the rules above):").size(14))
+                .push(included);
+        }
+
+        Scrollable::new(column)
+            .height(Length::Fill)
+            .width(Length::Fill)
+            .into()
+    }
+
+    /// What would be wrong with the config file if the Rules tab's edits were applied, checked the
+    /// same way as when it is loaded. Problems outside the edited rule set aren't listed.
+    fn draft_problems(&self) -> Vec<Diagnostic> {
+        let Some(draft) = &self.rule_draft else {
+            return Vec::new();
+        };
+        let mut config = self.config.clone();
+        *config.rules_mut() = draft.clone();
+        config::check_config(&self.config_file.path, &config).into_iter()
+            .filter(|problem| problem.profile == self.config.active_profile)
+            .collect()
+    }
+
+    /// Whether the Rules tab holds edits that differ from the saved rules.
+    fn has_unapplied_rules(&self) -> bool {
+        self.rule_draft.as_ref().is_some_and(|draft| draft != self.config.rules())
+    }
+
+    /// Whether the app has a rule of its own, matching just the executable.
+    fn has_group_rule(&self, exe_name: &str) -> bool {
+        self.config.rules().iter().any(|w| w.title.is_none() && w.executable.as_deref() == Some(exe_name))
+    }
+
+    /// Current value of an executable's group slider: what it was last set to, else the app's rule,
+    /// else the opacity of its first window.
+    fn group_value(&self, exe_name: &str) -> u8 {
+        if let Some(value) = self.group_opacity.get(exe_name) {
+            return *value;
+        }
+        self.config.rules().iter()
+            .find(|w| w.title.is_none() && w.executable.as_deref() == Some(exe_name))
+            .map(|rule| rule.opacity)
+            .or_else(|| self.windows.iter()
+                .find(|window| window.exe_name == exe_name)
+                .and_then(|window| window.transparency.trim_end_matches('%').parse().ok()))
+            .unwrap_or(100)
+    }
+
+    fn filter_matches(&self, window: &WindowInfo) -> bool {
+        let text = self.filter.text.trim().to_lowercase();
+        let text_matches = text.is_empty() || [&window.title, &window.exe_name, &window.class_name]
+            .iter()
+            .any(|field| field.to_lowercase().contains(&text));
+
+        text_matches
+            && (!self.filter.with_rule || self.config.find_rule(&window.title, &window.exe_name).is_some())
+            && (!self.filter.modified || ORIGINAL_TRANSPARENCY.lock().unwrap().contains_key(&(window.hwnd.0 as isize)))
+            && (!self.filter.layered || window.is_layered())
+    }
+
+    /// Makes `opacity` the rule for a window. It takes the place of an existing rule with the same
+    /// title and executable, so its priority doesn't change, and any copies of that rule are dropped.
+    /// Rules for the whole app, such as an `executable`-only group rule, are left alone, and a new
+    /// rule goes in front of the first one that would otherwise match the window first.
+    fn set_window_rule(&mut self, title: String, exe_name: String, opacity: u8) {
+        let rules = self.config.rules_mut();
+        // Every copy of the window's rule is at or after `at`, so dropping them doesn't move it
+        let at = rules.iter()
+            .position(|w| w.is_for_window(&title, &exe_name) || (w.enabled && w.matches(&title, &exe_name)))
+            .unwrap_or(rules.len());
+        rules.retain(|w| !w.is_for_window(&title, &exe_name));
+        rules.insert(at, config::WindowConfig {
+            title: Some(title),
+            executable: Some(exe_name),
+            opacity,
+            enabled: true,
+            source: None,
+        });
+    }
+
+    /// Saves `opacity` as the rule for `window`, replacing the rule with its title and executable.
+    fn persist_window(&mut self, window: HWND, opacity: u8, change: Option<ConfigChange>) {
+        let Some(title) = get_window_title(window) else {
+            return;
+        };
+        let exe_name = get_window_exe_label(window);
+
+        info!(title = %title, exe = %exe_name, hwnd = window.0 as isize, opacity, "saved window rule");
+        self.history.record(&self.config, change);
+        self.set_window_rule(title, exe_name, opacity);
+        self.save_config();
+
+        if self.selected().is_some_and(|index| self.windows[index].hwnd == window) {
+            self.persist_setting = true;
+        }
+    }
+
+    /// Saves the opacity staged by mouse wheel turns as the window's rule.
+    fn save_wheel_opacity(&mut self) {
+        if let Some((handle, opacity)) = self.wheel_pending.take() {
+            self.persist_window(HWND(handle as _), opacity, Some(ConfigChange::Transparency(handle)));
+        }
+    }
+
+    /// (Re)installs the mouse wheel hook if enabled, replacing the one installed before.
+    fn install_wheel_hook(&mut self) {
+        self.wheel_hook = None;
+        self.wheel_errors.clear();
+
+        let mouse_wheel = &self.config.mouse_wheel;
+        if !mouse_wheel.enabled {
+            return;
+        }
+        match hotkey::parse_modifiers(&mouse_wheel.modifier) {
+            Ok(modifiers) => {
+                let tx = self._tx.clone();
+                match wheel::install(modifiers, move |window, delta| {
+                    let _ = tx.unbounded_send(Message::WheelOpacity(window, delta));
+                }) {
+                    Ok(hook) => self.wheel_hook = Some(hook),
+                    Err(e) => self.wheel_errors.push(format!("Mouse wheel hook could not be installed: {}", e)),
+                }
+            }
+            Err(e) => self.wheel_errors.push(format!("Mouse wheel modifier {}", e)),
+        }
+        for error in &self.wheel_errors {
+            warn!("{}", error);
+        }
+    }
+
+    /// (Re)registers the configured global hotkeys, replacing any registered before.
+    fn register_hotkeys(&mut self) {
+        // Unregister the old ones first so unchanged hotkeys don't clash with themselves
+        self.hotkeys = None;
+        self.hotkey_errors.clear();
+
+        let hotkeys = &self.config.hotkeys;
+        let step = hotkeys.step as i16;
+        let actions = [
+            (&hotkeys.reset, Message::PanicReset),
+            (&hotkeys.step_up, Message::StepFocused(step)),
+            (&hotkeys.step_down, Message::StepFocused(-step)),
+            (&hotkeys.toggle, Message::ToggleFocused),
+            (&hotkeys.persist, Message::PersistFocused),
+        ];
+        let mut bindings = Vec::new();
+        for (text, message) in actions {
+            let Some(text) = text else { continue };
+            match Hotkey::parse(text) {
+                Ok(hotkey) => bindings.push((hotkey, message)),
+                Err(e) => self.hotkey_errors.push(format!("Hotkey {}", e)),
+            }
+        }
+
+        if !bindings.is_empty() {
+            let tx = self._tx.clone();
+            let (listener, failed) = hotkey::listen(bindings, move |message| {
+                let _ = tx.unbounded_send(message);
+            });
+            self.hotkeys = Some(listener);
+            for hotkey in failed {
+                self.hotkey_errors.push(format!("Hotkey {} is already in use by another app", hotkey));
+            }
+        }
+        for error in &self.hotkey_errors {
+            warn!("{}", error);
+        }
+    }
+
+    /// Rebuilds the tray icon so its menu reflects the current status.
+    fn update_tray(&mut self) {
+        // Drop the old icon first so two icons never show up at once
+        self.tray = None;
+        match build_tray(&self._tx, &self.config, &self.config_errors, &self.errors) {
+            Ok(tray) => self.tray = Some(tray),
+            Err(e) => {
+                warn!("could not rebuild tray icon: {}", e);
+                self.report(KesterError::Tray(e.to_string()));
+            }
+        }
+    }
+}
+
+/// Files to watch for changes. Falls back to just the main file when nothing was loaded.
+fn watched_paths(config: &Config, config_file: &ConfigFile) -> Vec<String> {
+    if config.watched_paths.is_empty() {
+        vec![config_file.path.clone()]
+    } else {
+        config.watched_paths.clone()
+    }
+}
+
+/// Makes sure fixing a broken included file is noticed, even if it never loaded successfully.
+fn watch_files_with_errors(watched_paths: &Mutex<Vec<String>>, errors: &[Diagnostic]) {
+    let mut watched_paths = watched_paths.lock().unwrap();
+    for diagnostic in errors {
+        if !watched_paths.contains(&diagnostic.file) {
+            watched_paths.push(diagnostic.file.clone());
+        }
+    }
+}
+
+fn build_tray(tx: &UnboundedSender<Message>, config: &Config, config_errors: &[Diagnostic], errors: &[KesterError]) -> Result<TrayItem, TIError> {
+    let mut tray = TrayItem::new("Transparency Manager", "tray_icon")?;
+
+    if let Some(last) = errors.last() {
+        let summary: String = last.to_string().chars().take(60).collect();
+        tray.add_label(&format!("{} error(s): {}", errors.len(), summary))?;
+    }
+
+    if let Some(first) = config_errors.first() {
+        let summary: String = first.to_string().chars().take(60).collect();
+        tray.add_label(&format!("{} config problem(s): {}", config_errors.len(), summary))?;
+    }
+
+    if !config.profiles.is_empty() {
+        let active = ProfileChoice(config.active_profile.clone());
+        tray.add_label(&format!("Profile: {}", active))?;
+
+        let choices = std::iter::once(ProfileChoice(None))
+            .chain(config.profiles.keys().map(|name| ProfileChoice(Some(name.clone()))));
+        for choice in choices.filter(|choice| *choice != active) {
+            let tx_profile = tx.clone();
+            let label = format!("Switch to {}", choice);
+            tray.add_menu_item(&label, move || {
+                let _ = tx_profile.unbounded_send(Message::SelectProfile(choice.clone()));
+            })?;
+        }
+        tray.inner_mut().add_separator()?;
+    }
+
+    if config.paused {
+        tray.add_label("Rules are paused")?;
+        let tx_resume = tx.clone();
+        tray.add_menu_item("Resume rules", move || {
+            let _ = tx_resume.unbounded_send(Message::TogglePaused(false));
+        })?;
+    }
+
+    let tx_panic = tx.clone();
+    tray.add_menu_item("Make all windows opaque", move || {
+        let _ = tx_panic.unbounded_send(Message::PanicReset);
+    })?;
+
+    let tx_undo = tx.clone();
+    tray.add_menu_item("Undo", move || {
+        let _ = tx_undo.unbounded_send(Message::Undo);
+    })?;
+
+    let tx_redo = tx.clone();
+    tray.add_menu_item("Redo", move || {
+        let _ = tx_redo.unbounded_send(Message::Redo);
+    })?;
+
+    let tx_show = tx.clone();
+    tray.add_menu_item("Show Window", move || {
+        let _ = tx_show.unbounded_send(Message::ShowWindow);
+    })?;
+
+    let tx_exit = tx.clone();
+    tray.add_menu_item("Exit", move || {
+        let _ = tx_exit.unbounded_send(Message::CloseRequested);
+    })?;
+
+    Ok(tray)
+}
+
+/// Completes after `duration`. The executor has no timers, so a thread does the waiting.
+async fn sleep(duration: Duration) {
+    let (done_tx, done_rx) = iced::futures::channel::oneshot::channel();
+    std::thread::spawn(move || {
+        std::thread::sleep(duration);
+        let _ = done_tx.send(());
+    });
+    let _ = done_rx.await;
+}
+
+/// Enumerates the windows and applies the config to them on a thread of its own, so a slow or
+/// hung app can't freeze the GUI. Progress is reported through `progress`.
+async fn refresh_windows(config: Config, progress: UnboundedSender<Message>) -> (Vec<WindowInfo>, Vec<KesterError>) {
+    let (done_tx, done_rx) = iced::futures::channel::oneshot::channel();
+    std::thread::spawn(move || {
+        let mut windows = enumerate_windows(|count| {
+            let _ = progress.unbounded_send(Message::RefreshProgress(count));
+        });
+        let errors = apply_config(&mut windows, &config);
+        let _ = done_tx.send((windows, errors));
+    });
+    done_rx.await.unwrap_or_default()
+}
+
+/// Lists the top-level windows kester manages, without changing any of them. `progress` is called
+/// with the number of windows found so far.
+fn enumerate_windows(mut progress: impl FnMut(usize)) -> Vec<WindowInfo> {
+    let mut context = EnumContext { windows: Vec::new(), progress: &mut progress };
+    unsafe {
+        let _ = EnumWindows(Some(enum_window), LPARAM(&mut context as *mut EnumContext as isize));
+    }
+    context.windows
+}
+
+/// State of one `EnumWindows` call, lent to `enum_window` through its `LPARAM`.
+struct EnumContext<'a> {
+    windows: Vec<WindowInfo>,
+    progress: &'a mut dyn FnMut(usize),
+}
+
+extern "system" fn enum_window(window: HWND, lparam: LPARAM) -> BOOL {
+    // Only valid during the `EnumWindows` call in `enumerate_windows`
+    let context = unsafe { &mut *(lparam.0 as *mut EnumContext) };
+    if !is_window_visible_and_normal(window) {
+        return true.into();
+    }
+
+    if let Some(title) = get_window_title(window) {
+        let window_info = WindowInfo {
+            id: window_id(window),
+            title,
+            exe_name: get_window_exe_label(window),
+            class_name: get_window_class_name(window),
+            transparency: transparency_label(window),
+            hwnd: window,
+            responding: is_window_responding(window),
+            last_apply: None,
+        };
+
+        if !window_info.responding {
+            info!(title = %window_info.title, exe = %window_info.exe_name, hwnd = window.0 as isize, "window is not responding, leaving it alone");
+        }
+        context.windows.push(window_info);
+        (context.progress)(context.windows.len());
+    }
     true.into()
 }
+
+/// Gives each listed window the opacity `config` has for it, skipping hung windows, and records
+/// the outcome in it. Windows kester changed that no rule or default applies to any more get
+/// their original opacity back, unless the user opted out. Returns the windows that couldn't be
+/// changed.
+fn apply_config(windows: &mut [WindowInfo], config: &Config) -> Vec<KesterError> {
+    let mut errors = Vec::new();
+    if config.paused {
+        return errors;
+    }
+    for window in windows.iter_mut().filter(|window| window.responding) {
+        let rule = config.find_rule_index(&window.title, &window.exe_name).map(|(index, _)| index);
+        let opacity = determine_opacity(&window.title, &window.exe_name, config);
+        match opacity {
+            Some(opacity) => debug!(title = %window.title, exe = %window.exe_name, hwnd = window.hwnd.0 as isize, rule = ?rule, opacity, "applying opacity"),
+            // A window that lost its rule through a reload, profile switch or resume goes back
+            None if config.restore_original_opacity && is_changed_by_kester(window.hwnd) => {
+                debug!(title = %window.title, exe = %window.exe_name, hwnd = window.hwnd.0 as isize, "restoring original opacity");
+            }
+            None => continue,
+        }
+        if let Err(failure) = apply_to_window(window, opacity) {
+            warn!(title = %window.title, exe = %window.exe_name, hwnd = window.hwnd.0 as isize, rule = ?rule, opacity, code = failure.code, "could not change window: {}", failure.reason);
+            errors.push(failure.to_error(&window.title));
+        }
+    }
+    errors
+}
+
+/// Sets `window` to `opacity`, or back to how it was before kester changed it for `None`, and
+/// records the outcome and the transparency now shown in it. A change Windows accepts but the
+/// window doesn't keep, as with some apps that draw their own windows, counts as a failure.
+fn apply_to_window(window: &mut WindowInfo, opacity: Option<u8>) -> Result<(), ApplyFailure> {
+    let result = match opacity {
+        Some(opacity) => set_window_transparency(window.hwnd, opacity),
+        None => restore_window_transparency(window.hwnd),
+    };
+    window.transparency = transparency_label(window.hwnd);
+
+    let outcome = match (result, opacity) {
+        (Err(e), _) => Err(ApplyFailure::new(&e, None)),
+        (Ok(()), Some(opacity)) if opacity < 100 && get_window_transparency(window.hwnd) != Some(percent_to_alpha(opacity)) => {
+            Err(ApplyFailure { code: 0, reason: "the window didn't keep the change".to_string(), hint: None })
+        }
+        (Ok(()), _) => Ok(()),
+    };
+    let outcome = outcome.map_err(|mut failure| {
+        failure.hint = failure_hint(window, &failure);
+        failure
+    });
+    window.last_apply = Some(outcome.clone());
+    outcome
+}
+
+/// Advice on a window kester couldn't change, from what can be told about it.
+fn failure_hint(window: &WindowInfo, failure: &ApplyFailure) -> Option<&'static str> {
+    let kester_elevated = is_process_elevated(unsafe { GetCurrentProcess() }) == Some(true);
+    // The token of an elevated process usually can't be read either, so an unknown elevation
+    // together with a refusal points the same way
+    let window_elevated = match window_process_elevated(window.hwnd) {
+        Some(elevated) => elevated,
+        None => failure.is_access_denied(),
+    };
+    if window_elevated && !kester_elevated {
+        Some("process is elevated - run kester as administrator to control it")
+    } else if UWP_FRAME_CLASSES.contains(&window.class_name.as_str()) {
+        Some("Store app - Windows draws these windows itself and won't make them see-through")
+    } else if failure.code == 0 {
+        Some("the app draws its window itself and undoes the change")
+    } else {
+        None
+    }
+}
+
+/// What went wrong with a window, as shown below it in the list.
+fn failure_text(failure: &ApplyFailure) -> Element<'_, Message> {
+    let code = if failure.code == 0 { String::new() } else { format!(" (0x{:08X})", failure.code as u32) };
+    Column::new()
+        .push(Text::new(format!("Could not change: {}{}", failure.reason.trim_end(), code))
+            .size(12)
+            .style(Color::from([0.8, 0.2, 0.2])))
+        .push_maybe(failure.hint.map(|hint| Text::new(hint).size(12).style(Color::from([0.8, 0.5, 0.0]))))
+        .into()
+}
+
+/// Whether the process that owns `window` runs as administrator, or `None` if that can't be told.
+fn window_process_elevated(window: HWND) -> Option<bool> {
+    unsafe {
+        let mut process_id: u32 = 0;
+        GetWindowThreadProcessId(window, Some(&mut process_id));
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, process_id).ok()?;
+        let elevated = is_process_elevated(process);
+        let _ = CloseHandle(process);
+        elevated
+    }
+}
+
+/// Whether `process` runs as administrator, or `None` if its token can't be read.
+fn is_process_elevated(process: HANDLE) -> Option<bool> {
+    unsafe {
+        let mut token = HANDLE::default();
+        OpenProcessToken(process, TOKEN_QUERY, &mut token).ok()?;
+        let mut elevation = TOKEN_ELEVATION::default();
+        let mut size = 0;
+        let result = GetTokenInformation(
+            token,
+            TokenElevation,
+            Some(&mut elevation as *mut TOKEN_ELEVATION as *mut _),
+            std::mem::size_of::<TOKEN_ELEVATION>() as u32,
+            &mut size,
+        );
+        let _ = CloseHandle(token);
+        result.ok().map(|()| elevation.TokenIsElevated != 0)
+    }
+}
+
+/// Whether the window's thread answers a no-op message within `HUNG_WINDOW_TIMEOUT_MS`.
+fn is_window_responding(window: HWND) -> bool {
+    unsafe {
+        if IsHungAppWindow(window).as_bool() {
+            return false;
+        }
+        let mut result = 0;
+        SendMessageTimeoutW(
+            window,
+            WM_NULL,
+            WPARAM(0),
+            LPARAM(0),
+            SMTO_ABORTIFHUNG | SMTO_BLOCK,
+            HUNG_WINDOW_TIMEOUT_MS,
+            Some(&mut result),
+        ).0 != 0
+    }
+}
 fn is_window_visible_and_normal(window: HWND) -> bool {
     unsafe {
         IsWindowVisible(window).as_bool() &&
@@ -440,6 +1905,11 @@ fn is_window_visible_and_normal(window: HWND) -> bool {
     }
 }
 
+/// Opacity as shown in the window list, `N/A` for windows that aren't layered.
+fn transparency_label(window: HWND) -> String {
+    get_window_transparency(window).map_or("N/A".to_string(), |a| format!("{}%", alpha_to_percent(a)))
+}
+
 fn get_window_transparency(window: HWND) -> Option<u8> {
     unsafe {
         let style = GetWindowLongW(window, GWL_EXSTYLE);
@@ -455,6 +1925,23 @@ fn get_window_transparency(window: HWND) -> Option<u8> {
     }
 }
 
+fn window_id(window: HWND) -> WindowId {
+    let mut process_started = 0;
+    unsafe {
+        let mut process_id: u32 = 0;
+        GetWindowThreadProcessId(window, Some(&mut process_id));
+
+        if let Ok(handle) = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, process_id) {
+            let (mut created, mut exited, mut kernel, mut user) = Default::default();
+            if GetProcessTimes(handle, &mut created, &mut exited, &mut kernel, &mut user).is_ok() {
+                process_started = (created.dwHighDateTime as u64) << 32 | created.dwLowDateTime as u64;
+            }
+            let _ = CloseHandle(handle);
+        }
+    }
+    WindowId { hwnd: window.0 as isize, process_started }
+}
+
 fn get_window_exe_name(window: HWND) -> Option<String> {
     unsafe {
         let mut process_id: u32 = 0;
@@ -472,32 +1959,161 @@ fn get_window_exe_name(window: HWND) -> Option<String> {
     }
 }
 fn set_window_transparency(window: HWND, percentage: u8) -> Result<(), windows::core::Error> {
+    remember_original_transparency(window);
     unsafe {
         let mut style = GetWindowLongW(window, GWL_EXSTYLE);
 
         if percentage == 100 {
             // Remove the layered window style to make it fully opaque
             style &= !WS_EX_LAYERED.0 as i32;
-            SetWindowLongW(window, GWL_EXSTYLE, style);
+            set_extended_style(window, style)?;
             let _ = RedrawWindow(window, None, None, RDW_FRAME | RDW_INVALIDATE | RDW_UPDATENOW);
             Ok(())
         } else {
             // Set or ensure the layered window style
             style |= WS_EX_LAYERED.0 as i32;
-            SetWindowLongW(window, GWL_EXSTYLE, style);
+            set_extended_style(window, style)?;
 
-            let alpha = (percentage as f32 * 255.0 / 100.0) as u8;
+            let alpha = percent_to_alpha(percentage);
             SetLayeredWindowAttributes(window, COLORREF(0), alpha, LWA_ALPHA)
         }
     }
 }
+
+/// Sets the extended window style, failing if Windows refuses, as it does for windows of
+/// processes running as administrator.
+unsafe fn set_extended_style(window: HWND, style: i32) -> Result<(), windows::core::Error> {
+    // The previous style is returned and may be 0, only the last error tells a failure apart
+    SetLastError(WIN32_ERROR(0));
+    if SetWindowLongW(window, GWL_EXSTYLE, style) == 0 {
+        let error = windows::core::Error::from_win32();
+        if error.code().is_err() {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
 fn determine_opacity(title: &str, exe_name: &str, config: &Config) -> Option<u8> {
-    for window_config in &config.specific_windows {
-        if window_config.title.as_ref().map_or(false, |t| title.contains(t)) ||
-            window_config.executable.as_ref().map_or(false, |e| exe_name.contains(e))
-        {
-            return Some(window_config.opacity);
+    match config.find_rule(title, exe_name) {
+        Some(rule) => Some(rule.opacity),
+        None => config.effective_default_opacity(),
+    }
+}
+
+/// Records how `window` looks before kester changes it for the first time.
+fn remember_original_transparency(window: HWND) {
+    let mut originals = ORIGINAL_TRANSPARENCY.lock().unwrap();
+    originals.entry(window.0 as isize).or_insert_with(|| unsafe {
+        let layered = GetWindowLongW(window, GWL_EXSTYLE) as u32 & WS_EX_LAYERED.0 != 0;
+        let mut color = COLORREF(0);
+        let mut alpha: u8 = 0;
+        let mut flags = LAYERED_WINDOW_ATTRIBUTES_FLAGS(0);
+        let attributes = (layered && GetLayeredWindowAttributes(window, Some(&mut color), Some(&mut alpha), Some(&mut flags)).is_ok())
+            .then_some((color, alpha, flags));
+        OriginalTransparency { layered, attributes }
+    });
+}
+
+/// Whether kester changed `window` and hasn't put it back yet.
+fn is_changed_by_kester(window: HWND) -> bool {
+    ORIGINAL_TRANSPARENCY.lock().unwrap().contains_key(&(window.0 as isize))
+}
+
+/// Puts `window` back the way it was before kester first changed it. Does nothing for windows
+/// kester never touched.
+fn restore_window_transparency(window: HWND) -> Result<(), windows::core::Error> {
+    let Some(original) = ORIGINAL_TRANSPARENCY.lock().unwrap().remove(&(window.0 as isize)) else {
+        return Ok(());
+    };
+    unsafe {
+        let mut style = GetWindowLongW(window, GWL_EXSTYLE);
+        if original.layered {
+            style |= WS_EX_LAYERED.0 as i32;
+            SetWindowLongW(window, GWL_EXSTYLE, style);
+            if let Some((color, alpha, flags)) = original.attributes {
+                SetLayeredWindowAttributes(window, color, alpha, flags)?;
+            }
+        } else {
+            style &= !WS_EX_LAYERED.0 as i32;
+            SetWindowLongW(window, GWL_EXSTYLE, style);
+            let _ = RedrawWindow(window, None, None, RDW_FRAME | RDW_INVALIDATE | RDW_UPDATENOW);
+        }
+        Ok(())
+    }
+}
+
+/// Restores every window kester changed that still exists and isn't hung.
+fn restore_all_window_transparency() {
+    let handles: Vec<isize> = ORIGINAL_TRANSPARENCY.lock().unwrap().keys().copied().collect();
+    for handle in handles {
+        let window = HWND(handle as _);
+        if unsafe { IsWindow(window) }.as_bool() && is_window_responding(window) {
+            // Called on the way out, the log is all that's left to report a failure in
+            if let Err(e) = restore_window_transparency(window) {
+                warn!(hwnd = handle, "could not restore original opacity: {}", e);
+            }
         }
     }
-    config.default_opacity
-}
\ No newline at end of file
+}
+
+/// Makes every visible window that is see-through at all fully opaque, whether or not kester
+/// changed it. Hung windows are skipped. Returns how many windows were changed.
+///
+/// The windows' original opacity is forgotten, so exiting afterwards leaves them opaque.
+fn make_all_windows_opaque() -> usize {
+    extern "system" fn make_opaque(window: HWND, lparam: LPARAM) -> BOOL {
+        let count = unsafe { &mut *(lparam.0 as *mut usize) };
+        let see_through = get_window_transparency(window).is_some_and(|alpha| alpha < 255);
+        if unsafe { IsWindowVisible(window) }.as_bool() && see_through && is_window_responding(window) && set_window_transparency(window, 100).is_ok() {
+            ORIGINAL_TRANSPARENCY.lock().unwrap().remove(&(window.0 as isize));
+            *count += 1;
+        }
+        true.into()
+    }
+
+    let mut count = 0usize;
+    unsafe {
+        let _ = EnumWindows(Some(make_opaque), LPARAM(&mut count as *mut usize as isize));
+    }
+    count
+}
+
+fn get_window_title(window: HWND) -> Option<String> {
+    let mut text: [u16; 512] = [0; 512];
+    let len = unsafe { GetWindowTextW(window, &mut text) };
+    (len > 0).then(|| String::from_utf16_lossy(&text[..len as usize]))
+}
+
+fn get_window_class_name(window: HWND) -> String {
+    let mut name: [u16; 256] = [0; 256];
+    let len = unsafe { GetClassNameW(window, &mut name) };
+    String::from_utf16_lossy(&name[..len.max(0) as usize])
+}
+
+/// Executable name as shown in the list and matched by rules.
+fn get_window_exe_label(window: HWND) -> String {
+    get_window_exe_name(window)
+        .unwrap_or_else(|| "Unknown".to_string())
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .collect()
+}
+
+/// The window that has the keyboard focus, for the hotkeys that act on it. A hung window is left
+/// alone, as changing its style would freeze the GUI until it answers.
+fn focused_window() -> Option<HWND> {
+    let window = unsafe { GetForegroundWindow() };
+    (!window.is_invalid() && is_window_responding(window)).then_some(window)
+}
+
+/// Converts an opacity in percent to a layered window alpha value, rounding to the nearest.
+fn percent_to_alpha(percentage: u8) -> u8 {
+    ((percentage as u32 * 255 + 50) / 100) as u8
+}
+
+/// Converts a layered window alpha value to an opacity in percent, rounding to the nearest so that
+/// `alpha_to_percent(percent_to_alpha(p)) == p`.
+fn alpha_to_percent(alpha: u8) -> u8 {
+    ((alpha as u32 * 100 + 127) / 255) as u8
+}
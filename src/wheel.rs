@@ -0,0 +1,136 @@
+use crate::hotkey::{ensure_message_queue, MOD_ALT, MOD_CONTROL, MOD_SHIFT, MOD_WIN};
+use std::cell::RefCell;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use windows::Win32::Foundation::{HWND, LPARAM, LRESULT, WPARAM};
+use windows::Win32::System::Threading::GetCurrentThreadId;
+use windows::Win32::UI::Input::KeyboardAndMouse::{
+    GetAsyncKeyState, SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, KEYBDINPUT, KEYEVENTF_KEYUP, VIRTUAL_KEY,
+    VK_CONTROL, VK_LWIN, VK_MENU, VK_RWIN, VK_SHIFT,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    CallNextHookEx, GetAncestor, GetMessageW, PostThreadMessageW, SetWindowsHookExW, UnhookWindowsHookEx,
+    WindowFromPoint, GA_ROOT, HC_ACTION, MSG, MSLLHOOKSTRUCT, WH_MOUSE_LL, WM_MOUSEWHEEL, WM_QUIT,
+};
+
+/// Wheel distance of one notch.
+pub const WHEEL_DELTA: i32 = 120;
+
+/// An unassigned virtual key, tapped to keep a released Alt from opening the target window's menu.
+const VK_MENU_MASK: VIRTUAL_KEY = VIRTUAL_KEY(0xE8);
+
+type WheelHandler = Box<dyn Fn(isize, i16) + Send>;
+
+thread_local! {
+    /// Modifiers to wait for and where to report wheel turns, read by the hook procedure. Each hook
+    /// runs on its own thread, so a new hook never sees the handler of the one it replaces.
+    static HANDLER: RefCell<Option<(u32, WheelHandler)>> = const { RefCell::new(None) };
+}
+
+/// Keeps the mouse hook installed while alive. Dropping it removes the hook, and only returns
+/// once it's gone.
+pub struct WheelHook {
+    thread_id: u32,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for WheelHook {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = PostThreadMessageW(self.thread_id, WM_QUIT, WPARAM(0), LPARAM(0));
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Installs a low-level mouse hook that calls `on_wheel` with the top-level window under the
+/// cursor and the wheel delta whenever the wheel turns while exactly `modifiers` (`MOD_*` flags)
+/// are held. Those wheel turns are swallowed, so the window itself doesn't scroll.
+///
+/// Low-level hooks are called on the installing thread's message loop, so the hook gets a thread
+/// of its own.
+pub fn install<F>(modifiers: u32, on_wheel: F) -> Result<WheelHook, windows::core::Error>
+where
+    F: Fn(isize, i16) + Send + 'static,
+{
+    let handler: WheelHandler = Box::new(on_wheel);
+
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        HANDLER.with(|slot| *slot.borrow_mut() = Some((modifiers, handler)));
+        ensure_message_queue();
+        let hook = match unsafe { SetWindowsHookExW(WH_MOUSE_LL, Some(mouse_proc), None, 0) } {
+            Ok(hook) => hook,
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+                return;
+            }
+        };
+        let _ = ready_tx.send(Ok(unsafe { GetCurrentThreadId() }));
+
+        let mut msg = MSG::default();
+        // GetMessageW returns 0 for WM_QUIT and -1 on errors
+        while unsafe { GetMessageW(&mut msg, None, 0, 0) }.0 > 0 {}
+
+        unsafe {
+            let _ = UnhookWindowsHookEx(hook);
+        }
+    });
+
+    let thread_id = ready_rx.recv().expect("Mouse hook thread exited before installing")?;
+    Ok(WheelHook { thread_id, thread: Some(thread) })
+}
+
+unsafe extern "system" fn mouse_proc(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    if code == HC_ACTION as i32 && wparam.0 as u32 == WM_MOUSEWHEEL {
+        let info = &*(lparam.0 as *const MSLLHOOKSTRUCT);
+        let handled = HANDLER.with(|slot| {
+            let handler = slot.borrow();
+            let Some((modifiers, on_wheel)) = handler.as_ref() else { return false };
+            let window = GetAncestor(WindowFromPoint(info.pt), GA_ROOT);
+            if held_modifiers() != *modifiers || window == HWND::default() {
+                return false;
+            }
+            // The high word holds the signed wheel delta
+            let delta = (info.mouseData >> 16) as u16 as i16;
+            on_wheel(window.0 as isize, delta);
+            if modifiers & MOD_ALT != 0 {
+                mask_alt_release();
+            }
+            true
+        });
+        if handled {
+            return LRESULT(1);
+        }
+    }
+    CallNextHookEx(None, code, wparam, lparam)
+}
+
+fn held_modifiers() -> u32 {
+    let down = |key: VIRTUAL_KEY| unsafe { GetAsyncKeyState(key.0 as i32) } < 0;
+    [
+        (down(VK_CONTROL), MOD_CONTROL),
+        (down(VK_MENU), MOD_ALT),
+        (down(VK_SHIFT), MOD_SHIFT),
+        (down(VK_LWIN) || down(VK_RWIN), MOD_WIN),
+    ]
+    .iter()
+    .filter(|(held, _)| *held)
+    .fold(0, |modifiers, (_, flag)| modifiers | flag)
+}
+
+/// Windows opens a window's menu when Alt is pressed and released with nothing in between. Tapping
+/// an unassigned key tells it Alt was used as a modifier.
+fn mask_alt_release() {
+    let input = |flags| INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 {
+            ki: KEYBDINPUT { wVk: VK_MENU_MASK, dwFlags: flags, ..Default::default() },
+        },
+    };
+    unsafe {
+        SendInput(&[input(Default::default()), input(KEYEVENTF_KEYUP)], std::mem::size_of::<INPUT>() as i32);
+    }
+}